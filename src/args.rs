@@ -1,56 +1,373 @@
-use clap::Parser;
-
-/// Command line arguments for yaydl.
-///
-/// Now with ENV Support!
-#[derive(Parser, Default, Debug)]
-#[clap(version, about = "Yet Another Youtube Down Loader", long_about = None)]
-pub struct Args {
-    #[clap(long = "only-audio", short = 'x', help = "Only keeps the audio stream")]
-    pub onlyaudio: bool,
-
-    #[clap(
-        long = "keep-temp-file",
-        short = 'k',
-        help = "Keeps all downloaded data even with --only-audio"
-    )]
-    pub keeptempfile: bool,
-
-    #[clap(long, short = 'v', help = "Talks more while the URL is processed")]
-    pub verbose: bool,
-
-    #[clap(
-        long = "audio-format",
-        short = 'f',
-        help = "Sets the target audio format (only if --only-audio is used).\nSpecify the file extension here.",
-        default_value = "mp3"
-    )]
-    pub audioformat: String,
-
-    #[clap(long = "output", short = 'o', help = "Sets the output file name")]
-    pub outputfile: Option<String>,
-
-    #[clap(
-        long,
-        help = "The port of your web driver (required for some sites)",
-        env = "YAYDL_WEBDRIVER_PORT"
-    )]
-    pub webdriver: Option<u16>,
-
-    #[clap(help = "Sets the input URL to use", index = 1)]
-    pub url: String,
-
-    #[clap(
-        long = "invidious-instance",
-        short = 'i',
-        help = "Sets the Invidious instance to use",
-        env = "YAYDL_INVIDIOUS_INSTANCE"
-    )]
-    pub invidious_instance: Option<String>,
-}
-
-impl Args {
-    pub fn parse_webdriver(&self) -> u16 {
-        self.webdriver.unwrap_or(0)
-    }
-}
+use std::fs;
+use std::io::{self, Read};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use yaydl::{AudioQuality, HttpClient, Overwrites};
+
+/// Command line arguments for yaydl.
+///
+/// Now with ENV Support!
+#[derive(Parser, Default, Debug)]
+#[clap(version, about = "Yet Another Youtube Down Loader", long_about = None)]
+pub struct Args {
+    #[clap(long = "only-audio", short = 'x', help = "Only keeps the audio stream")]
+    pub onlyaudio: bool,
+
+    #[clap(
+        long = "keep-temp-file",
+        short = 'k',
+        help = "Keeps all downloaded data: the separate video and audio\nstreams of merged downloads, the file before a conversion\nand the thumbnail"
+    )]
+    pub keeptempfile: bool,
+
+    #[clap(
+        long = "embed-metadata",
+        help = "Writes the title, the uploader, the date, the description\nand the chapters into the file"
+    )]
+    pub embedmetadata: bool,
+
+    #[clap(
+        long = "embed-thumbnail",
+        help = "Writes the thumbnail into the file as its cover art"
+    )]
+    pub embedthumbnail: bool,
+
+    #[clap(long, short = 'v', help = "Talks more while the URL is processed")]
+    pub verbose: bool,
+
+    #[clap(
+        long = "audio-format",
+        short = 'f',
+        help = "Sets the target audio format (only if --only-audio is used):\nmp3, aac, opus, vorbis, flac, wav or any other file extension.\nAAC and Opus streams are kept as they are if possible.",
+        default_value = "mp3"
+    )]
+    pub audioformat: String,
+
+    #[clap(
+        long = "audio-quality",
+        help = "Sets the quality of converted audio: a VBR level from 0 (best) to 10 (worst)\nor a bitrate like \"128K\""
+    )]
+    pub audioquality: Option<AudioQuality>,
+
+    #[clap(
+        long,
+        help = "Selects the format to download, e.g. \"best[height<=720]\" or \"bestvideo+bestaudio\".\nSee --list-formats for the available ones."
+    )]
+    pub format: Option<String>,
+
+    #[clap(
+        long = "list-formats",
+        short = 'F',
+        help = "Lists the available formats instead of downloading"
+    )]
+    pub listformats: bool,
+
+    #[clap(
+        long = "concurrent-fragments",
+        short = 'N',
+        help = "Sets how many playlist segments are downloaded at the same time",
+        default_value = "1"
+    )]
+    pub concurrentfragments: usize,
+
+    #[clap(
+        long,
+        help = "Sets how many connections download a single file at the same time",
+        default_value = "1"
+    )]
+    pub connections: usize,
+
+    #[clap(
+        long,
+        help = "Sets how often a failed page fetch or download is retried",
+        default_value = "3"
+    )]
+    pub retries: usize,
+
+    #[clap(
+        long = "fragment-retries",
+        help = "Sets how often a failed segment of a playlist is retried",
+        default_value = "3"
+    )]
+    pub fragmentretries: usize,
+
+    #[clap(
+        long = "limit-rate",
+        short = 'r',
+        value_name = "RATE",
+        value_parser = parse_rate,
+        help = "Caps the download speed in bytes per second, e.g. \"500K\" or \"2M\""
+    )]
+    pub limitrate: Option<u64>,
+
+    #[clap(
+        long = "output",
+        short = 'o',
+        help = "Sets the output file name template, e.g.\n\"%(uploader)s/%(upload_date)s - %(title)s [%(id)s].%(ext)s\".\nKnown fields: id, title, uploader, upload_date, duration, ext,\nformat_id, width, height, resolution",
+        default_value = "%(title)s.%(ext)s"
+    )]
+    pub outputtemplate: String,
+
+    #[clap(
+        long = "no-overwrites",
+        short = 'w',
+        help = "Skips the download if the file exists already\n(default: the new file gets a number like \"Title (1).mp4\")"
+    )]
+    pub nooverwrites: bool,
+
+    #[clap(
+        long = "force-overwrites",
+        conflicts_with = "nooverwrites",
+        help = "Replaces existing files and starts interrupted downloads over"
+    )]
+    pub forceoverwrites: bool,
+
+    #[clap(
+        long = "restrict-filenames",
+        help = "Only uses ASCII letters, digits, \"_\", \"-\" and \".\" in file names"
+    )]
+    pub restrictfilenames: bool,
+
+    #[clap(
+        long = "windows-filenames",
+        help = "Makes file names valid on Windows, even on other systems"
+    )]
+    pub windowsfilenames: bool,
+
+    #[clap(
+        long,
+        short = 'P',
+        help = "Sets the directory to write the files to (default: the current one)"
+    )]
+    pub paths: Option<String>,
+
+    #[clap(
+        long,
+        help = "The port of your web driver (required for some sites)",
+        env = "YAYDL_WEBDRIVER_PORT"
+    )]
+    pub webdriver: Option<u16>,
+
+    #[clap(
+        long = "ffmpeg-location",
+        help = "Sets the ffmpeg binary to use instead of the one from the PATH",
+        env = "YAYDL_FFMPEG"
+    )]
+    pub ffmpeglocation: Option<String>,
+
+    #[clap(
+        value_name = "URL",
+        required_unless_present = "batchfile",
+        help = "Sets the input URLs to use (\"-\" reads them from the standard input)"
+    )]
+    pub urls: Vec<String>,
+
+    #[clap(
+        long = "batch-file",
+        short = 'a',
+        value_name = "FILE",
+        help = "Reads more URLs from FILE, one per line (\"-\" for the standard input).\nLines starting with \"#\" are ignored."
+    )]
+    pub batchfile: Option<String>,
+
+    #[clap(
+        long = "invidious-instance",
+        short = 'i',
+        help = "Sets the Invidious instance to use",
+        env = "YAYDL_INVIDIOUS_INSTANCE"
+    )]
+    pub invidious_instance: Option<String>,
+
+    #[clap(
+        long,
+        help = "Sets the proxy to use instead of the one from the environment"
+    )]
+    pub proxy: Option<String>,
+
+    #[clap(
+        long = "user-agent",
+        help = "Sets the User-Agent header of all requests"
+    )]
+    pub useragent: Option<String>,
+
+    #[clap(
+        long = "add-header",
+        value_name = "NAME:VALUE",
+        value_parser = parse_header,
+        help = "Sends an additional header with all requests (can be used more than once)"
+    )]
+    pub addheader: Vec<(String, String)>,
+
+    #[clap(
+        long = "socket-timeout",
+        value_name = "SECONDS",
+        help = "Sets how long to wait for a server before giving up"
+    )]
+    pub sockettimeout: Option<u64>,
+
+    #[clap(
+        long = "max-redirects",
+        help = "Sets how many redirects to follow (0 to follow none)"
+    )]
+    pub maxredirects: Option<u32>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Sends the cookies from a Netscape cookie file (e.g. cookies.txt)"
+    )]
+    pub cookies: Option<String>,
+
+    #[clap(
+        long = "save-cookies",
+        requires = "cookies",
+        help = "Writes the cookies back into the --cookies file after the run"
+    )]
+    pub savecookies: bool,
+}
+
+impl Args {
+    pub fn parse_webdriver(&self) -> u16 {
+        self.webdriver.unwrap_or(0)
+    }
+
+    // returns the URLs from the command line and the batch file.
+    pub fn urls(&self) -> Result<Vec<String>> {
+        let mut urls = vec![];
+        for url in &self.urls {
+            match url.as_str() {
+                "-" => urls.extend(parse_batch(&read_stdin()?)),
+                _ => urls.push(url.clone()),
+            }
+        }
+
+        if let Some(path) = &self.batchfile {
+            let batch = match path.as_str() {
+                "-" => read_stdin()?,
+                _ => fs::read_to_string(path)
+                    .with_context(|| format!("Could not read the batch file {}", path))?,
+            };
+            urls.extend(parse_batch(&batch));
+        }
+
+        Ok(urls)
+    }
+
+    pub fn http_client(&self) -> Result<HttpClient> {
+        let mut builder = HttpClient::builder();
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(user_agent) = &self.useragent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        for (name, value) in &self.addheader {
+            builder = builder.header(name, value);
+        }
+
+        if let Some(seconds) = self.sockettimeout {
+            builder = builder
+                .connect_timeout(Duration::from_secs(seconds))
+                .read_timeout(Duration::from_secs(seconds));
+        }
+
+        if let Some(count) = self.maxredirects {
+            builder = builder.max_redirects(count);
+        }
+
+        if let Some(path) = &self.cookies {
+            builder = builder.cookies(path);
+        }
+
+        builder.build()
+    }
+
+    pub fn overwrites(&self) -> Overwrites {
+        match (self.nooverwrites, self.forceoverwrites) {
+            (true, _) => Overwrites::Never,
+            (_, true) => Overwrites::Always,
+            _ => Overwrites::Rename,
+        }
+    }
+}
+
+fn read_stdin() -> Result<String> {
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .context("Could not read the URLs from the standard input")?;
+    Ok(input)
+}
+
+// returns the URLs in <batch>, skipping empty lines and comments.
+fn parse_batch(batch: &str) -> impl Iterator<Item = String> + '_ {
+    batch
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+}
+
+// splits "Name: value" into the name and the value of a header.
+fn parse_header(header: &str) -> std::result::Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!(
+            "\"{}\" is not a header like \"Name: value\"",
+            header
+        )),
+    }
+}
+
+// returns the bytes per second in a rate like "2M" or "1.5K"
+// (K, M and G are multiples of 1024).
+fn parse_rate(rate: &str) -> std::result::Result<u64, String> {
+    let invalid = || format!("\"{}\" is not a rate like \"500K\" or \"2M\"", rate);
+
+    let number = rate.trim().trim_end_matches(['b', 'B']);
+    let (number, factor) = match number.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&number[..i], 1u64 << 10),
+        Some((i, 'm' | 'M')) => (&number[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&number[..i], 1 << 30),
+        _ => (number, 1),
+    };
+
+    match number.trim().parse::<f64>() {
+        Ok(number) if number.is_finite() && number > 0.0 => {
+            Ok(((number * factor as f64) as u64).max(1))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("100"), Ok(100));
+        assert_eq!(parse_rate("500K"), Ok(500 * 1024));
+        assert_eq!(parse_rate("1.5k"), Ok(1536));
+        assert_eq!(parse_rate("2M"), Ok(2097152));
+        assert_eq!(parse_rate("2MB"), Ok(2097152));
+        assert_eq!(parse_rate(" 1g "), Ok(1 << 30));
+
+        // Tiny rates are not rounded down to no limit:
+        assert_eq!(parse_rate("0.1"), Ok(1));
+    }
+
+    #[test]
+    fn invalid_rates() {
+        for rate in ["", "K", "2X", "0", "-1", "nan", "inf", "2 M B"] {
+            assert!(parse_rate(rate).is_err(), "{}", rate);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - cookies.rs file -
+
+// Reads and writes cookie jars in the Netscape format which browser
+// extensions, curl and other downloaders export. Each line is a cookie:
+//
+//   domain <TAB> subdomains <TAB> path <TAB> secure <TAB> expires <TAB> name <TAB> value
+//
+// "subdomains" and "secure" are TRUE or FALSE, "expires" is a Unix time
+// (0 for a session cookie). A "#HttpOnly_" prefix of the domain marks
+// a cookie which scripts must not see; other lines starting with '#'
+// are comments.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use cookie_store::{Cookie, CookieDomain, CookieError, CookieExpiration, CookieStore, RawCookie};
+use time::OffsetDateTime;
+use url::Url;
+
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+// returns the cookies from <path>, an empty jar if there is no such file yet.
+pub fn load(path: &Path) -> Result<CookieStore> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CookieStore::default()),
+        Err(e) => return Err(e).context(format!("Could not read {}", path.display())),
+    };
+
+    let mut store = CookieStore::default();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || (line.starts_with('#') && !line.starts_with(HTTP_ONLY_PREFIX))
+        {
+            continue;
+        }
+
+        let invalid = |reason: &str| {
+            anyhow!(
+                "Invalid cookie in {}, line {}: {}",
+                path.display(),
+                number + 1,
+                reason
+            )
+        };
+
+        let (raw_cookie, url) = parse_line(line).map_err(|e| invalid(&e.to_string()))?;
+
+        // The jar may contain cookies which have expired since:
+        match store.insert_raw(&raw_cookie, &url) {
+            Ok(_) | Err(CookieError::Expired) => {}
+            Err(e) => return Err(invalid(&e.to_string())),
+        }
+    }
+
+    Ok(store)
+}
+
+// returns the cookie in <line> and a URL it could have been set from.
+fn parse_line(line: &str) -> Result<(RawCookie<'static>, Url)> {
+    let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [domain, subdomains, path, secure, expires, name, value @ ..] = fields.as_slice() else {
+        return Err(anyhow!("expected 7 fields separated by tabs"));
+    };
+
+    let host = domain.trim_start_matches('.');
+    let secure = flag(secure)?;
+    let mut raw_cookie = RawCookie::build((name.to_string(), value.join("\t")))
+        .path(path.to_string())
+        .secure(secure)
+        .http_only(http_only);
+
+    // A cookie for the subdomains, too, is what a Domain attribute sets:
+    if flag(subdomains)? || domain.starts_with('.') {
+        raw_cookie = raw_cookie.domain(host.to_string());
+    }
+
+    let expires: i64 = expires.parse().context("expected a Unix time")?;
+    if expires > 0 {
+        raw_cookie = raw_cookie.expires(OffsetDateTime::from_unix_timestamp(expires)?);
+    }
+
+    let scheme = if secure { "https" } else { "http" };
+    let url = Url::parse(&format!("{}://{}{}", scheme, host, path))?;
+    Ok((raw_cookie.build(), url))
+}
+
+fn flag(value: &str) -> Result<bool> {
+    match value.to_ascii_uppercase().as_str() {
+        "TRUE" => Ok(true),
+        "FALSE" => Ok(false),
+        _ => Err(anyhow!("expected TRUE or FALSE instead of \"{}\"", value)),
+    }
+}
+
+// writes <cookies> to <path>. A cookie which appears more than once
+// (with the same name, domain and path) is written as it was seen last.
+pub fn save<'a>(path: &Path, cookies: impl Iterator<Item = &'a Cookie<'static>>) -> Result<()> {
+    let mut lines = BTreeMap::new();
+    for cookie in cookies.filter(|cookie| !cookie.is_expired()) {
+        let (domain, subdomains) = match &cookie.domain {
+            CookieDomain::HostOnly(host) => (host.clone(), "FALSE"),
+            CookieDomain::Suffix(suffix) => (format!(".{}", suffix), "TRUE"),
+            _ => continue,
+        };
+
+        let expires = match cookie.expires {
+            CookieExpiration::AtUtc(time) => time.unix_timestamp(),
+            CookieExpiration::SessionEnd => 0,
+        };
+
+        let prefix = match cookie.http_only() {
+            Some(true) => HTTP_ONLY_PREFIX,
+            _ => "",
+        };
+
+        let secure = match cookie.secure() {
+            Some(true) => "TRUE",
+            _ => "FALSE",
+        };
+
+        lines.insert(
+            (
+                domain.clone(),
+                cookie.path.to_string(),
+                cookie.name().to_string(),
+            ),
+            format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                prefix,
+                domain,
+                subdomains,
+                &*cookie.path,
+                secure,
+                expires,
+                cookie.name(),
+                cookie.value()
+            ),
+        );
+    }
+
+    let mut contents = String::from("# Netscape HTTP Cookie File\n");
+    contents.push_str("# This file was written by yaydl.\n\n");
+    contents.extend(lines.into_values());
+
+    fs::write(path, contents).context(format!("Could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process;
+
+    // returns a path in the temporary directory which is unique to this test run.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("yaydl-{}-{}.txt", process::id(), name))
+    }
+
+    #[test]
+    fn parse_lines() {
+        let (cookie, url) = parse_line("example.com\tFALSE\t/\tFALSE\t0\tid\tabc").unwrap();
+        assert_eq!((cookie.name(), cookie.value()), ("id", "abc"));
+        assert_eq!(cookie.domain(), None);
+        assert_eq!(cookie.secure(), Some(false));
+        assert_eq!(cookie.http_only(), Some(false));
+        assert_eq!(cookie.expires(), None);
+        assert_eq!(url.as_str(), "http://example.com/");
+
+        // Subdomains, secure, an expiry date and a tab in the value:
+        let (cookie, url) =
+            parse_line(".example.com\tTRUE\t/videos\tTRUE\t2000000000\tid\ta\tb").unwrap();
+        assert_eq!(cookie.value(), "a\tb");
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(
+            cookie
+                .expires_datetime()
+                .map(OffsetDateTime::unix_timestamp),
+            Some(2000000000)
+        );
+        assert_eq!(url.as_str(), "https://example.com/videos");
+
+        let (cookie, url) =
+            parse_line("#HttpOnly_example.com\tfalse\t/\tfalse\t0\tsession\t1").unwrap();
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(url.host_str(), Some("example.com"));
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(parse_line("example.com\tFALSE\t/\tFALSE\t0").is_err());
+        assert!(parse_line("example.com FALSE / FALSE 0 id abc").is_err());
+        assert!(parse_line("example.com\tYES\t/\tFALSE\t0\tid\tabc").is_err());
+        assert!(parse_line("example.com\tFALSE\t/\tFALSE\tnever\tid\tabc").is_err());
+    }
+
+    #[test]
+    fn load_and_save() {
+        let path = temp_path("cookies");
+        fs::write(
+            &path,
+            "# Netscape HTTP Cookie File\r\n\
+             \r\n\
+             # a comment\r\n\
+             .example.com\tTRUE\t/\tFALSE\t2000000000\tb\t2\r\n\
+             #HttpOnly_example.com\tFALSE\t/\tTRUE\t0\ta\t1\r\n\
+             example.com\tFALSE\t/\tFALSE\t1000\texpired\t0\r\n",
+        )
+        .unwrap();
+
+        let store = load(&path).unwrap();
+        assert_eq!(store.iter_any().count(), 2);
+
+        save(&path, store.iter_any()).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            saved,
+            "# Netscape HTTP Cookie File\n\
+             # This file was written by yaydl.\n\n\
+             .example.com\tTRUE\t/\tFALSE\t2000000000\tb\t2\n\
+             #HttpOnly_example.com\tFALSE\t/\tTRUE\t0\ta\t1\n"
+        );
+    }
+
+    #[test]
+    fn load_errors() {
+        assert_eq!(load(&temp_path("missing")).unwrap().iter_any().count(), 0);
+
+        let path = temp_path("invalid");
+        fs::write(&path, "# comment\nexample.com\tFALSE\t/\n").unwrap();
+        let error = load(&path).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(error.contains("line 2"), "{}", error);
+    }
+}
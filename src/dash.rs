@@ -0,0 +1,699 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - dash.rs file -
+
+// MPEG-DASH manifests (MPD) list one or more representations (qualities)
+// per stream. Usually, the video and the audio are separate streams,
+// so yaydl downloads one of each and lets ffmpeg put them together.
+//
+// The formats of a manifest have the URL of the manifest with the
+// representation ID as fragment, e.g. "https://.../manifest.mpd#137".
+
+use anyhow::{anyhow, Context, Result};
+use regex::{Captures, Regex};
+use roxmltree::{Document, Node};
+use url::Url;
+
+use crate::definitions::{HandlerContext, VideoFormat};
+use crate::download::{download, download_segments, mux_audio, DownloadOptions, Segment};
+use crate::prelude::split_codecs;
+use crate::retry::with_retries;
+
+// One quality of one stream:
+struct Representation {
+    id: String,
+    mime_type: String,
+    codecs: Option<String>,
+    bandwidth: u64,
+    width: Option<u64>,
+    height: Option<u64>,
+    media: Media,
+}
+
+// Where the media data of a representation is:
+enum Media {
+    // A single file (SegmentBase), which is downloaded like any other file.
+    File(String),
+    Segments(Vec<Segment>),
+}
+
+impl Representation {
+    fn is_audio(&self) -> bool {
+        self.mime_type.starts_with("audio/")
+    }
+
+    fn ext(&self) -> &str {
+        match (self.mime_type.ends_with("/webm"), self.is_audio()) {
+            (true, _) => "webm",
+            (false, true) => "m4a",
+            (false, false) => "mp4",
+        }
+    }
+}
+
+// Parses an ISO 8601 duration like "PT1H2M3.5S" into seconds.
+fn parse_duration(duration: &str) -> Option<f64> {
+    let duration_re = Regex::new(
+        r"^P(?:(?P<D>\d+)D)?(?:T(?:(?P<H>\d+)H)?(?:(?P<M>\d+)M)?(?:(?P<S>\d+(?:\.\d+)?)S)?)?$",
+    )
+    .unwrap();
+    let captures = duration_re.captures(duration.trim())?;
+    let part = |name: &str| -> f64 {
+        captures
+            .name(name)
+            .and_then(|m| m.as_str().parse().ok())
+            .unwrap_or(0.0)
+    };
+
+    Some(part("D") * 86400.0 + part("H") * 3600.0 + part("M") * 60.0 + part("S"))
+}
+
+// Parses a byte range like "100-199" into its offset and its length.
+fn parse_range(range: &str) -> Option<(u64, u64)> {
+    let (first, last) = range.split_once('-')?;
+    let first: u64 = first.trim().parse().ok()?;
+    let last: u64 = last.trim().parse().ok()?;
+    Some((first, last.checked_sub(first)? + 1))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+// returns the first value of the attribute <name> in <nodes>, as
+// representations inherit from their adaptation set and period.
+fn attribute<'a>(nodes: &[Option<Node<'a, '_>>], name: &str) -> Option<&'a str> {
+    nodes.iter().flatten().find_map(|node| node.attribute(name))
+}
+
+// returns the URL of <node> with respect to its BaseURL element.
+fn base_url(node: Node, parent: &Url) -> Result<Url> {
+    match child(node, "BaseURL").and_then(|base| base.text()) {
+        Some(text) => parent
+            .join(text.trim())
+            .with_context(|| format!("Invalid BaseURL {}", text)),
+        None => Ok(parent.clone()),
+    }
+}
+
+// Replaces the identifiers ($Number$, $Time$ etc.) in a SegmentTemplate URL.
+fn fill_template(template: &str, id: &str, bandwidth: u64, number: u64, time: u64) -> String {
+    let identifier_re = Regex::new(
+        r"\$(?:(?P<NAME>RepresentationID|Number|Bandwidth|Time)(?:%0(?P<WIDTH>\d+)d)?)?\$",
+    )
+    .unwrap();
+
+    identifier_re
+        .replace_all(template, |captures: &Captures| {
+            let width = captures
+                .name("WIDTH")
+                .and_then(|w| w.as_str().parse().ok())
+                .unwrap_or(0);
+
+            match captures.name("NAME").map(|name| name.as_str()) {
+                None => "$".to_string(),
+                Some("RepresentationID") => id.to_string(),
+                Some("Number") => format!("{:0width$}", number, width = width),
+                Some("Bandwidth") => format!("{:0width$}", bandwidth, width = width),
+                _ => format!("{:0width$}", time, width = width),
+            }
+        })
+        .to_string()
+}
+
+// Finds the segments of the representation <nodes>[0] with the URL <base>.
+fn media(
+    nodes: &[Option<Node>],
+    id: &str,
+    bandwidth: u64,
+    base: &Url,
+    period_duration: Option<f64>,
+) -> Result<Media> {
+    let segment = |url: &str, range: Option<(u64, u64)>| -> Result<Segment> {
+        Ok(Segment {
+            url: base.join(url)?.to_string(),
+            range,
+            encryption: None,
+        })
+    };
+
+    // SegmentTemplate: segment URLs are built from a pattern.
+    let templates: Vec<Option<Node>> = nodes
+        .iter()
+        .map(|node| node.and_then(|node| child(node, "SegmentTemplate")))
+        .collect();
+
+    if templates.iter().any(Option::is_some) {
+        let mut segments = vec![];
+
+        if let Some(initialization) = attribute(&templates, "initialization") {
+            segments.push(segment(
+                &fill_template(initialization, id, bandwidth, 0, 0),
+                None,
+            )?);
+        }
+
+        let media = attribute(&templates, "media")
+            .ok_or_else(|| anyhow!("The SegmentTemplate of {} has no media attribute.", id))?;
+        let start_number: u64 = attribute(&templates, "startNumber").map_or(Ok(1), str::parse)?;
+        let timescale: u64 = attribute(&templates, "timescale").map_or(Ok(1), str::parse)?;
+
+        let timeline = templates
+            .iter()
+            .flatten()
+            .find_map(|template| child(*template, "SegmentTimeline"));
+
+        let mut number = start_number;
+        match timeline {
+            // The timeline lists the start time and duration of all segments:
+            Some(timeline) => {
+                let period_end = period_duration.map(|d| (d * timescale as f64) as u64);
+                let mut time = 0;
+
+                for s in timeline.children().filter(|s| s.has_tag_name("S")) {
+                    time = s.attribute("t").map_or(Ok(time), str::parse)?;
+                    let duration: u64 = s
+                        .attribute("d")
+                        .ok_or_else(|| anyhow!("The SegmentTimeline of {} is invalid.", id))?
+                        .parse()?;
+                    if duration == 0 {
+                        return Err(anyhow!("The SegmentTimeline of {} is invalid.", id));
+                    }
+
+                    // A negative repeat count repeats the segment until the end of the period:
+                    let repeat: i64 = s.attribute("r").map_or(Ok(0), str::parse)?;
+                    let count = match (repeat, period_end) {
+                        (r, _) if r >= 0 => r as u64 + 1,
+                        (_, Some(end)) => end.saturating_sub(time).div_ceil(duration),
+                        _ => return Err(anyhow!("Cannot repeat the segments of {}.", id)),
+                    };
+
+                    for _ in 0..count {
+                        segments.push(segment(
+                            &fill_template(media, id, bandwidth, number, time),
+                            None,
+                        )?);
+                        number += 1;
+                        time += duration;
+                    }
+                }
+            }
+            // Otherwise, all segments have the same duration:
+            None => {
+                let duration: u64 = attribute(&templates, "duration")
+                    .ok_or_else(|| anyhow!("The SegmentTemplate of {} has no duration.", id))?
+                    .parse()?;
+                let period_duration = period_duration
+                    .ok_or_else(|| anyhow!("The duration of the manifest is unknown."))?;
+                let count = (period_duration * timescale as f64 / duration as f64).ceil() as u64;
+
+                for index in 0..count {
+                    segments.push(segment(
+                        &fill_template(media, id, bandwidth, number, index * duration),
+                        None,
+                    )?);
+                    number += 1;
+                }
+            }
+        }
+
+        return Ok(Media::Segments(segments));
+    }
+
+    // SegmentList: all segment URLs are listed.
+    if let Some(list) = nodes
+        .iter()
+        .flatten()
+        .find_map(|node| child(*node, "SegmentList"))
+    {
+        let mut segments = vec![];
+
+        if let Some(initialization) = child(list, "Initialization") {
+            segments.push(segment(
+                initialization.attribute("sourceURL").unwrap_or(""),
+                initialization.attribute("range").and_then(parse_range),
+            )?);
+        }
+
+        for segment_url in list.children().filter(|s| s.has_tag_name("SegmentURL")) {
+            segments.push(segment(
+                segment_url.attribute("media").unwrap_or(""),
+                segment_url.attribute("mediaRange").and_then(parse_range),
+            )?);
+        }
+
+        return Ok(Media::Segments(segments));
+    }
+
+    // SegmentBase (or nothing at all): the representation is a single file.
+    // It can be huge, so it is streamed to the disk rather than fetched as
+    // a single segment. Without a BaseURL of its own, <base> would be the
+    // manifest (or a directory) instead:
+    match nodes[0].and_then(|node| child(node, "BaseURL")) {
+        Some(_) => Ok(Media::File(base.to_string())),
+        None => Err(anyhow!(
+            "The representation {} has neither a BaseURL nor segments.",
+            id
+        )),
+    }
+}
+
+fn parse_manifest(text: &str, url: &Url) -> Result<Vec<Representation>> {
+    let document = Document::parse(text).with_context(|| format!("Invalid manifest {}", url))?;
+    let mpd = document.root_element();
+    if !mpd.has_tag_name("MPD") {
+        return Err(anyhow!("{} is no DASH manifest.", url));
+    }
+
+    if mpd.attribute("type") == Some("dynamic") {
+        return Err(anyhow!("Live streams are not supported."));
+    }
+
+    // Only the first period is downloaded:
+    let period = child(mpd, "Period").ok_or_else(|| anyhow!("{} has no period.", url))?;
+    let period_duration = period
+        .attribute("duration")
+        .or(mpd.attribute("mediaPresentationDuration"))
+        .and_then(parse_duration);
+    let period_base = base_url(period, &base_url(mpd, url)?)?;
+
+    let mut representations = vec![];
+    for adaptation_set in period
+        .children()
+        .filter(|node| node.has_tag_name("AdaptationSet"))
+    {
+        let adaptation_base = base_url(adaptation_set, &period_base)?;
+
+        for representation in adaptation_set
+            .children()
+            .filter(|node| node.has_tag_name("Representation"))
+        {
+            let nodes = [Some(representation), Some(adaptation_set), Some(period)];
+            let id = attribute(&nodes, "id").unwrap_or_default().to_string();
+            let bandwidth = attribute(&nodes, "bandwidth")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0);
+
+            // Skip subtitles, thumbnails and so on:
+            let mime_type = match (
+                attribute(&nodes, "mimeType"),
+                attribute(&nodes, "contentType"),
+            ) {
+                (Some(mime_type), _) => mime_type.to_string(),
+                (None, Some(content_type)) => format!("{}/mp4", content_type),
+                (None, None) => continue,
+            };
+            if !mime_type.starts_with("audio/") && !mime_type.starts_with("video/") {
+                continue;
+            }
+
+            representations.push(Representation {
+                media: media(
+                    &nodes,
+                    &id,
+                    bandwidth,
+                    &base_url(representation, &adaptation_base)?,
+                    period_duration,
+                )?,
+                id,
+                mime_type,
+                codecs: attribute(&nodes, "codecs").map(str::to_string),
+                bandwidth,
+                width: attribute(&nodes, "width").and_then(|w| w.parse().ok()),
+                height: attribute(&nodes, "height").and_then(|h| h.parse().ok()),
+            });
+        }
+    }
+
+    Ok(representations)
+}
+
+fn fetch_manifest(
+    agent: &ureq::Agent,
+    url: &Url,
+    retries: usize,
+    verbose: bool,
+) -> Result<(Vec<Representation>, Url)> {
+    let what = format!("Fetching the manifest {}", url);
+    let (text, base) = with_retries(retries, verbose, &what, || {
+        let response = agent.get(url.as_str()).call()?;
+
+        // Relative URLs are relative to where a redirect led to:
+        let base = Url::parse(response.get_url())?;
+        Ok((response.into_string()?, base))
+    })?;
+
+    Ok((parse_manifest(&text, &base)?, base))
+}
+
+// returns the representation with the best audio quality, if any.
+fn best_audio(representations: &[Representation]) -> Option<&Representation> {
+    representations
+        .iter()
+        .filter(|representation| representation.is_audio())
+        .max_by_key(|representation| representation.bandwidth)
+}
+
+// returns one format per representation, sorted by their bandwidth.
+fn representation_formats(representations: &[Representation], url: &Url) -> Vec<VideoFormat> {
+    let has_audio = best_audio(representations).is_some();
+
+    let mut formats: Vec<VideoFormat> = representations
+        .iter()
+        .map(|representation| {
+            let (vcodec, acodec) = representation
+                .codecs
+                .as_deref()
+                .map_or((None, None), split_codecs);
+
+            let mut format_url = url.clone();
+            format_url.set_fragment(Some(&representation.id));
+
+            VideoFormat {
+                id: format!("dash-{}", representation.id),
+                url: format_url.to_string(),
+                ext: representation.ext().to_string(),
+                is_dash: true,
+                width: representation.width,
+                height: representation.height,
+                // Video representations have no audio if there are separate audio ones:
+                video_only: !representation.is_audio() && acodec.is_none() && has_audio,
+                audio_only: representation.is_audio(),
+                vcodec,
+                acodec,
+                bitrate: Some(representation.bandwidth / 1000),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    formats.sort_by_key(|format| format.bitrate);
+    formats
+}
+
+// returns the representations of the manifest at <url> as formats.
+pub fn manifest_formats(ctx: &HandlerContext, url: &str) -> Result<Vec<VideoFormat>> {
+    let (representations, base) = fetch_manifest(
+        &ctx.agent(url)?,
+        &Url::parse(url)?,
+        ctx.retries,
+        ctx.verbose,
+    )?;
+    Ok(representation_formats(&representations, &base))
+}
+
+pub fn download_from_manifest(
+    agent: &ureq::Agent,
+    url: &str,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    if options.verbose {
+        println!("Found a DASH manifest. Fetching ...");
+    }
+
+    let mut url = Url::parse(url)?;
+    let representation_id = url.fragment().map(str::to_string);
+    url.set_fragment(None);
+
+    let (representations, base) = fetch_manifest(agent, &url, options.retries, options.verbose)?;
+    let find = |id: &str| {
+        representations
+            .iter()
+            .find(|representation| representation.id == id)
+            .ok_or_else(|| anyhow!("The manifest {} has no representation {}.", url, id))
+    };
+
+    // A format of a manifest is a single representation. Without one,
+    // pick them like formats (e.g. a video and an audio representation):
+    let chosen: Vec<&Representation> = match representation_id {
+        Some(id) => vec![find(&id)?],
+        None => {
+            let formats = representation_formats(&representations, &base);
+            options
+                .format_selector
+                .select_all(&formats)
+                .unwrap_or_else(|| formats.last().into_iter().collect())
+                .iter()
+                .map(|format| find(format.id.strip_prefix("dash-").unwrap_or(&format.id)))
+                .collect::<Result<_>>()?
+        }
+    };
+
+    let Some(representation) = chosen.first() else {
+        return Err(anyhow!("The manifest {} has no representations.", url));
+    };
+
+    if options.verbose {
+        println!("Choosing the representation {}.", representation.id);
+    }
+
+    download_representation(agent, &url, representation, filename, options)?;
+
+    // The audio is usually a separate stream:
+    if let Some(audio) = chosen.get(1) {
+        if options.verbose {
+            println!("Fetching the audio representation {} ...", audio.id);
+        }
+
+        let audio_filename = format!("{}.audio", filename);
+        download_representation(agent, &url, audio, &audio_filename, options)?;
+
+        mux_audio(filename, &audio_filename, &options.ffmpeg)?;
+    }
+
+    Ok(())
+}
+
+fn download_representation(
+    agent: &ureq::Agent,
+    url: &Url,
+    representation: &Representation,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    match &representation.media {
+        Media::File(file_url) => download(agent, file_url, filename, options),
+        Media::Segments(segments) => download_segments(
+            agent,
+            &format!("{}#{}", url, representation.id),
+            segments,
+            filename,
+            options,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATES: &str = r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" mediaPresentationDuration="PT10S">
+  <BaseURL>http://cdn.example.com/videos/</BaseURL>
+  <Period>
+    <BaseURL>p1/</BaseURL>
+    <AdaptationSet mimeType="video/mp4" codecs="avc1.4d401f">
+      <BaseURL>video/</BaseURL>
+      <SegmentTemplate timescale="10" startNumber="5"
+          initialization="$RepresentationID$/init.mp4"
+          media="$RepresentationID$/$Number%03d$-$Time$.m4s">
+        <SegmentTimeline>
+          <S t="0" d="20" r="1"/>
+          <S d="30" r="-1"/>
+        </SegmentTimeline>
+      </SegmentTemplate>
+      <Representation id="720" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio">
+      <Representation id="a" bandwidth="128000" codecs="mp4a.40.2">
+        <BaseURL>../audio/</BaseURL>
+        <SegmentTemplate duration="4" media="seg$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet mimeType="text/vtt">
+      <Representation id="subtitles"><BaseURL>en.vtt</BaseURL></Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    const LISTS: &str = r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" mediaPresentationDuration="PT4S">
+  <Period>
+    <AdaptationSet mimeType="video/webm">
+      <Representation id="list" bandwidth="1000">
+        <BaseURL>list.webm</BaseURL>
+        <SegmentList>
+          <Initialization range="0-99"/>
+          <SegmentURL mediaRange="100-199"/>
+          <SegmentURL media="other.webm" mediaRange="200-299"/>
+        </SegmentList>
+      </Representation>
+      <Representation id="file" bandwidth="2000">
+        <BaseURL>file.webm</BaseURL>
+        <SegmentBase indexRange="0-99"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    fn parse(text: &str) -> Result<Vec<Representation>> {
+        parse_manifest(
+            text,
+            &Url::parse("https://example.com/dash/manifest.mpd").unwrap(),
+        )
+    }
+
+    // returns the URLs and ranges of the segments of <representation>.
+    fn segments(representation: &Representation) -> Vec<(&str, Option<(u64, u64)>)> {
+        match &representation.media {
+            Media::Segments(segments) => segments
+                .iter()
+                .map(|segment| (segment.url.as_str(), segment.range))
+                .collect(),
+            Media::File(url) => panic!("{} is no segment list", url),
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("P1DT30M"), Some(88200.0));
+        assert_eq!(parse_duration(" PT0.25S "), Some(0.25));
+        assert_eq!(parse_duration("P1D"), Some(86400.0));
+        assert_eq!(parse_duration("1H"), None);
+        assert_eq!(parse_duration("PT1.2.3S"), None);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("100-199"), Some((100, 100)));
+        assert_eq!(parse_range("7-7"), Some((7, 1)));
+        assert_eq!(parse_range("200-100"), None);
+        assert_eq!(parse_range("100"), None);
+        assert_eq!(parse_range("a-b"), None);
+    }
+
+    #[test]
+    fn templates() {
+        assert_eq!(
+            fill_template("$RepresentationID$/$Number%05d$.m4s", "v1", 0, 42, 0),
+            "v1/00042.m4s"
+        );
+        assert_eq!(
+            fill_template("t$Time$-b$Bandwidth$-n$Number$", "v1", 128000, 3, 90000),
+            "t90000-b128000-n3"
+        );
+        assert_eq!(fill_template("$Time%03d$", "v1", 0, 0, 1234), "1234");
+        assert_eq!(
+            fill_template("a$$b$Unknown$", "v1", 0, 0, 0),
+            "a$b$Unknown$"
+        );
+    }
+
+    #[test]
+    fn segment_templates() {
+        let representations = parse(TEMPLATES).unwrap();
+        assert_eq!(representations.len(), 2);
+
+        // The BaseURLs of the MPD, the period and the adaptation set add up,
+        // and r="-1" repeats until the period ends after 100 time units:
+        let video = &representations[0];
+        assert_eq!((video.id.as_str(), video.ext()), ("720", "mp4"));
+        assert_eq!((video.width, video.height), (Some(1280), Some(720)));
+        assert_eq!(
+            segments(video),
+            [
+                ("http://cdn.example.com/videos/p1/video/720/init.mp4", None),
+                ("http://cdn.example.com/videos/p1/video/720/005-0.m4s", None),
+                (
+                    "http://cdn.example.com/videos/p1/video/720/006-20.m4s",
+                    None
+                ),
+                (
+                    "http://cdn.example.com/videos/p1/video/720/007-40.m4s",
+                    None
+                ),
+                (
+                    "http://cdn.example.com/videos/p1/video/720/008-70.m4s",
+                    None
+                ),
+            ]
+        );
+
+        // Segments of a fixed duration cover the whole period (10 / 4 seconds):
+        let audio = &representations[1];
+        assert!(audio.is_audio());
+        assert_eq!(audio.ext(), "m4a");
+        assert_eq!(
+            segments(audio),
+            [
+                ("http://cdn.example.com/videos/audio/seg1.m4s", None),
+                ("http://cdn.example.com/videos/audio/seg2.m4s", None),
+                ("http://cdn.example.com/videos/audio/seg3.m4s", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn segment_lists_and_files() {
+        let representations = parse(LISTS).unwrap();
+
+        assert_eq!(
+            segments(&representations[0]),
+            [
+                ("https://example.com/dash/list.webm", Some((0, 100))),
+                ("https://example.com/dash/list.webm", Some((100, 100))),
+                ("https://example.com/dash/other.webm", Some((200, 100))),
+            ]
+        );
+
+        assert_eq!(representations[1].ext(), "webm");
+        match &representations[1].media {
+            Media::File(url) => assert_eq!(url, "https://example.com/dash/file.webm"),
+            Media::Segments(_) => panic!("file.webm is a single file"),
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let url = Url::parse("https://example.com/dash/manifest.mpd").unwrap();
+        let formats = representation_formats(&parse(TEMPLATES).unwrap(), &url);
+
+        // Sorted by the bitrate:
+        let ids: Vec<&str> = formats.iter().map(|format| format.id.as_str()).collect();
+        assert_eq!(ids, ["dash-a", "dash-720"]);
+        assert_eq!(formats[1].url, "https://example.com/dash/manifest.mpd#720");
+        assert!(formats[0].audio_only && formats[1].video_only);
+    }
+
+    #[test]
+    fn invalid_manifests() {
+        // Without a BaseURL, the file would be the manifest itself:
+        let no_media = r#"<MPD><Period><AdaptationSet mimeType="video/mp4">
+            <Representation id="v"><SegmentBase indexRange="0-9"/></Representation>
+        </AdaptationSet></Period></MPD>"#;
+        assert!(parse(no_media).is_err());
+
+        assert!(parse(r#"<MPD type="dynamic"><Period/></MPD>"#).is_err());
+        assert!(parse("<MPD/>").is_err());
+        assert!(parse("<html/>").is_err());
+        assert!(parse("not xml").is_err());
+
+        // r="-1" needs to know where the period ends:
+        let endless = TEMPLATES.replace(r#" mediaPresentationDuration="PT10S""#, "");
+        assert!(parse(&endless).is_err());
+    }
+}
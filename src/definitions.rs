@@ -17,50 +17,162 @@
 // - definitions.rs file -
 
 use anyhow::Result;
+use regex::Regex;
 
-use crate::VIDEO;
+use crate::http::HttpClient;
+use crate::retry::with_retries;
+use crate::webdriver::WebDriver;
+
+// Settings which are passed from the downloader to the site definitions:
+#[derive(Debug, Clone, Default)]
+pub struct HandlerContext {
+    // The port that runs the web driver client, 0 if there is none.
+    pub webdriver_port: u16,
+    // The Invidious instance to use, None for the default one.
+    pub invidious_instance: Option<String>,
+    // The HTTP client to send all requests with.
+    pub http: HttpClient,
+    // How often a failed page fetch is retried.
+    pub retries: usize,
+    // Tells about the retries, among other things.
+    pub verbose: bool,
+    // The browser session which is shared by all pages that need one.
+    pub(crate) webdriver: WebDriver,
+}
+
+impl HandlerContext {
+    // returns a ureq agent for <url> that honours the network settings.
+    pub fn agent(&self, url: &str) -> Result<ureq::Agent> {
+        self.http.agent(url)
+    }
+
+    // returns the page at <url>, retrying as often as the user allows.
+    pub fn fetch(&self, url: &str) -> Result<String> {
+        with_retries(
+            self.retries,
+            self.verbose,
+            &format!("Fetching {}", url),
+            || Ok(self.http.get(url)?.call()?.into_string()?),
+        )
+    }
+
+    // returns the page at <url> as the web driver sees it after
+    // running <scripts> on it.
+    pub fn browse(&self, url: &str, scripts: &[&str]) -> Result<String> {
+        self.webdriver.source(self.webdriver_port, url, scripts)
+    }
+}
+
+// A single downloadable version of a video:
+#[derive(Debug, Clone, Default)]
+pub struct VideoFormat {
+    // The ID of the format on this site (e.g. "hls-720p").
+    pub id: String,
+    // The download URL of the video or playlist.
+    pub url: String,
+    // The file extension of the video (e.g. "mp4").
+    pub ext: String,
+    // true, if <url> is a playlist.
+    pub is_playlist: bool,
+    // true, if <url> is a DASH manifest (see dash.rs).
+    pub is_dash: bool,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    // The codecs (e.g. "avc1.64001F" and "mp4a.40.2"), if known.
+    pub vcodec: Option<String>,
+    pub acodec: Option<String>,
+    // The total bitrate in kbit/s.
+    pub bitrate: Option<u64>,
+    // true, if the format has no audio stream.
+    pub video_only: bool,
+    // true, if the format has no video stream.
+    pub audio_only: bool,
+}
+
+impl VideoFormat {
+    // returns the resolution as "<width>x<height>" (or whatever is known).
+    pub fn resolution(&self) -> String {
+        match (self.width, self.height) {
+            _ if self.audio_only => "audio only".to_string(),
+            (Some(width), Some(height)) => format!("{}x{}", width, height),
+            (None, Some(height)) => format!("{}p", height),
+            _ => "unknown".to_string(),
+        }
+    }
+}
+
+// A chapter of a video. It ends where the next one starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    // The start in seconds.
+    pub start: u64,
+    pub title: String,
+}
+
+impl Chapter {
+    // returns the chapters listed in a video description, e.g. "0:00 Intro"
+    // or "1:02:03 - The End" on separate lines. Like YouTube, this needs
+    // at least two of them in order, the first one starting at 0:00.
+    pub fn from_description(description: &str) -> Vec<Chapter> {
+        let chapter_re =
+            Regex::new(r"^\s*(?:(\d+):)?(\d{1,2}):(\d{2})\s*[-–—:|]?\s+(\S.*?)\s*$").unwrap();
+
+        let mut chapters: Vec<Chapter> = vec![];
+        for captures in description
+            .lines()
+            .filter_map(|line| chapter_re.captures(line))
+        {
+            let number = |i| {
+                captures
+                    .get(i)
+                    .map_or(0, |m| m.as_str().parse().unwrap_or(0))
+            };
+            let start = number(1) * 3600 + number(2) * 60 + number(3);
+
+            if chapters.last().is_some_and(|last| last.start >= start) {
+                // Not a list of chapters then.
+                return vec![];
+            }
+
+            chapters.push(Chapter {
+                start,
+                title: captures[4].to_string(),
+            });
+        }
+
+        match chapters.len() >= 2 && chapters[0].start == 0 {
+            true => chapters,
+            false => vec![],
+        }
+    }
+}
+
+// Everything a site definition knows about a video:
+#[derive(Debug, Clone, Default)]
+pub struct VideoMetadata {
+    pub id: String,
+    pub title: String,
+    pub uploader: Option<String>,
+    // The duration in seconds.
+    pub duration: Option<u64>,
+    // The URL of the thumbnail image.
+    pub thumbnail: Option<String>,
+    pub description: Option<String>,
+    // The upload date as YYYYMMDD.
+    pub upload_date: Option<String>,
+    // The chapters, if the site knows them.
+    pub chapters: Vec<Chapter>,
+    // The available formats, sorted from worst to best.
+    pub formats: Vec<VideoFormat>,
+}
 
 // Define the public interface for site definitions:
 pub trait SiteDefinition: Sync + Send {
     // true, if this site can handle <url>.
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool;
-
-    // true, if the video exists.
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        webdriver_port: u16,
-    ) -> Result<bool>;
-
-    // true, if the URL is a playlist.
-    fn is_playlist<'a>(&'a self, url: &'a str, webdriver_port: u16) -> Result<bool>;
-
-    // returns the title of a video.
-    fn find_video_title<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        webdriver_port: u16,
-    ) -> Result<String>;
-
-    // returns the download URL of a video or playlist.
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        webdriver_port: u16,
-        onlyaudio: bool,
-    ) -> Result<String>;
-
-    // returns the file extension of the video (e.g. "mp4").
-    fn find_video_file_extension<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        webdriver_port: u16,
-        onlyaudio: bool,
-    ) -> Result<String>;
+    fn can_handle_url<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> bool;
+
+    // returns the metadata of the video at <url>, None if there is no video.
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>>;
 
     // returns the name of the site (e.g. "YouTube").
     fn display_name(&self) -> String;
@@ -68,3 +180,6 @@ pub trait SiteDefinition: Sync + Send {
     // true, if this site needs a web driver.
     fn web_driver_required(&self) -> bool;
 }
+
+// Collect all site definitions which were pushed to the inventory:
+inventory::collect!(&'static dyn SiteDefinition);
@@ -16,16 +16,74 @@
 // Yet Another Youtube Down Loader
 // - download.rs file -
 
-use anyhow::Result;
-use indicatif::{ProgressBar, ProgressStyle};
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use anyhow::{anyhow, Context, Result};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use m3u8_rs::{
+    AlternativeMedia, AlternativeMediaType, Key, KeyMethod, MasterPlaylist, MediaPlaylist, Playlist,
+};
 use nom::Finish;
+use serde_json::{json, Value};
 use std::{
+    collections::{BTreeMap, HashMap},
     fs,
-    io::{self, copy, Read},
-    path::Path,
+    io::{self, copy, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Condvar, Mutex,
+    },
+    thread,
 };
 use url::Url;
 
+use crate::definitions::{HandlerContext, VideoFormat};
+use crate::ffmpeg::Ffmpeg;
+use crate::prelude::split_codecs;
+use crate::ratelimit::RateLimiter;
+use crate::retry::{with_retries, DEFAULT_RETRIES};
+use crate::selector::FormatSelector;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+// Settings for the actual downloads:
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub verbose: bool,
+    // Chooses the variant of master playlists.
+    pub format_selector: FormatSelector,
+    // How many playlist segments are fetched at the same time.
+    pub concurrent_fragments: usize,
+    // How many connections download a single file at the same time.
+    pub connections: usize,
+    // How often a failed download (or playlist) is retried.
+    pub retries: usize,
+    // How often a failed playlist segment is retried.
+    pub fragment_retries: usize,
+    // Caps the speed of all downloads together.
+    pub rate_limit: RateLimiter,
+    // Keeps the progress bars of simultaneous downloads apart.
+    pub progress: MultiProgress,
+    // Merges separate audio streams.
+    pub ffmpeg: Ffmpeg,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            format_selector: FormatSelector::default(),
+            concurrent_fragments: 1,
+            connections: 1,
+            retries: DEFAULT_RETRIES,
+            fragment_retries: DEFAULT_RETRIES,
+            rate_limit: RateLimiter::default(),
+            progress: MultiProgress::new(),
+            ffmpeg: Ffmpeg::default(),
+        }
+    }
+}
+
 struct DownloadProgress<'a, R> {
     inner: R,
     progress_bar: &'a ProgressBar,
@@ -39,44 +97,699 @@ impl<R: Read> Read for DownloadProgress<'_, R> {
     }
 }
 
-pub fn download_from_playlist(url: &str, filename: &str, verbose: bool) -> Result<()> {
+// Downloads go into <filename>.part first. Only complete files
+// are renamed to <filename>:
+fn part_filename(filename: &str) -> String {
+    format!("{}.part", filename)
+}
+
+// Throws away what an interrupted download of <filename> left behind.
+pub(crate) fn remove_partial(filename: &str) -> Result<()> {
+    for path in [
+        PathBuf::from(part_filename(filename)),
+        Journal::path_for(filename),
+    ] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// The journal of a playlist download, stored next to the file
+// as <filename>.journal, so an interrupted download can be resumed:
+struct Journal {
+    path: PathBuf,
+    playlist_url: String,
+    segment_count: usize,
+    last_completed_index: Option<usize>,
+    // The size of the file after the last completed segment.
+    byte_offset: u64,
+}
+
+impl Journal {
+    fn path_for(filename: &str) -> PathBuf {
+        PathBuf::from(format!("{}.journal", filename))
+    }
+
+    fn new(filename: &str, playlist_url: &str, segment_count: usize) -> Self {
+        Self {
+            path: Self::path_for(filename),
+            playlist_url: playlist_url.to_string(),
+            segment_count,
+            last_completed_index: None,
+            byte_offset: 0,
+        }
+    }
+
+    fn load(filename: &str) -> Option<Self> {
+        let path = Self::path_for(filename);
+        let journal: Value = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+
+        Some(Self {
+            playlist_url: journal["playlist_url"].as_str()?.to_string(),
+            segment_count: journal["segment_count"].as_u64()? as usize,
+            last_completed_index: journal["last_completed_index"]
+                .as_u64()
+                .map(|index| index as usize),
+            byte_offset: journal["byte_offset"].as_u64()?,
+            path,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let journal = json!({
+            "playlist_url": self.playlist_url,
+            "segment_count": self.segment_count,
+            "last_completed_index": self.last_completed_index,
+            "byte_offset": self.byte_offset,
+        });
+        fs::write(&self.path, journal.to_string())?;
+        Ok(())
+    }
+
+    fn remove(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    // returns the index of the first segment that still needs to be downloaded.
+    fn next_index(&self) -> usize {
+        self.last_completed_index.map_or(0, |index| index + 1)
+    }
+}
+
+// A single segment of a media playlist (or its initialization section):
+pub(crate) struct Segment {
+    pub(crate) url: String,
+    // The offset and the length of the segment, if it is only a part of <url>.
+    pub(crate) range: Option<(u64, u64)>,
+    // The AES-128 key and IV, if the segment is encrypted.
+    pub(crate) encryption: Option<([u8; 16], [u8; 16])>,
+}
+
+impl Segment {
+    fn decrypt(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.encryption {
+            None => Ok(data),
+            // An initialization section in front of its EXT-X-KEY is not
+            // encrypted, but m3u8-rs does not tell which tag came first.
+            // MP4 files start with an "ftyp" box, encrypted data does not:
+            Some(_) if data.get(4..8) == Some(b"ftyp") => Ok(data),
+            Some((key, iv)) => Aes128CbcDec::new(key.into(), iv.into())
+                .decrypt_padded_vec_mut::<Pkcs7>(&data)
+                .map_err(|_| anyhow!("Could not decrypt the segment {}.", self.url)),
+        }
+    }
+}
+
+// Parses a hexadecimal IV like "0x1234..." into 16 bytes.
+fn parse_iv(iv: &str) -> Result<[u8; 16]> {
+    let hex = iv.trim_start_matches("0x").trim_start_matches("0X");
+    let number = u128::from_str_radix(hex, 16).map_err(|_| anyhow!("Invalid IV: {}", iv))?;
+    Ok(number.to_be_bytes())
+}
+
+// Finds the URL, the byte range and the encryption of all segments of <playlist>.
+// fMP4 playlists have an initialization section (EXT-X-MAP) which is put
+// in front of the segments it applies to.
+fn segments(
+    agent: &ureq::Agent,
+    url: &Url,
+    playlist: &MediaPlaylist,
+    options: &DownloadOptions,
+) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut keys: HashMap<String, [u8; 16]> = HashMap::new();
+
+    // Where the previous byte range ended, see segment_range():
+    let mut previous_end: Option<(String, u64)> = None;
+
+    // An EXT-X-KEY applies to all following segments until the next one:
+    let mut current_key: Option<&Key> = None;
+
+    for (index, segment) in playlist.segments.iter().enumerate() {
+        if segment.key.is_some() {
+            current_key = segment.key.as_ref();
+        }
+
+        // m3u8-rs does not parse "METHOD=NONE" without an IV, but it
+        // still ends the encryption:
+        if segment.unknown_tags.iter().any(|tag| {
+            tag.tag == "X-KEY"
+                && tag
+                    .rest
+                    .as_deref()
+                    .is_some_and(|r| r.contains("METHOD=NONE"))
+        }) {
+            current_key = None;
+        }
+
+        let encryption = match current_key {
+            None => None,
+            Some(key) => match &key.method {
+                KeyMethod::None => None,
+                KeyMethod::AES128 => {
+                    let key_uri = key
+                        .uri
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("The playlist {} has a key without URI.", url))?;
+                    let key_url = resolve_uri(url, key_uri)?;
+
+                    // Fetch every key only once:
+                    if !keys.contains_key(&key_url) {
+                        let what = format!("Fetching the key {}", key_url);
+                        let key_data =
+                            with_retries(options.fragment_retries, options.verbose, &what, || {
+                                let mut key_data = vec![];
+                                agent
+                                    .get(&key_url)
+                                    .call()?
+                                    .into_reader()
+                                    .read_to_end(&mut key_data)?;
+                                Ok(key_data)
+                            })?;
+                        let key_bytes: [u8; 16] = key_data
+                            .try_into()
+                            .map_err(|_| anyhow!("The key {} is no AES-128 key.", key_url))?;
+                        keys.insert(key_url.clone(), key_bytes);
+                    }
+
+                    // Without an explicit IV, the media sequence number is the IV:
+                    let iv = match &key.iv {
+                        Some(iv) => parse_iv(iv)?,
+                        None => (playlist.media_sequence as u128 + index as u128).to_be_bytes(),
+                    };
+
+                    Some((keys[&key_url], iv))
+                }
+                method => {
+                    return Err(anyhow!(
+                        "The playlist {} uses the unsupported encryption {}.",
+                        url,
+                        method
+                    ))
+                }
+            },
+        };
+
+        if let Some(map) = &segment.map {
+            // The initialization section is only encrypted if the key has an
+            // explicit IV (and if the key came first, see Segment::decrypt()):
+            let explicit_iv = current_key.is_some_and(|key| key.iv.is_some());
+
+            let map_url = resolve_uri(url, &map.uri)?;
+            segments.push(Segment {
+                range: segment_range(&map_url, map.byte_range.as_ref(), &mut None),
+                url: map_url,
+                encryption: encryption.filter(|_| explicit_iv),
+            });
+        }
+
+        let segment_url = resolve_uri(url, &segment.uri)?;
+        let range = segment_range(&segment_url, segment.byte_range.as_ref(), &mut previous_end);
+
+        segments.push(Segment {
+            url: segment_url,
+            range,
+            encryption,
+        });
+    }
+
+    Ok(segments)
+}
+
+// returns the offset and the length of the byte range <range> of <url>, if
+// any. A range without an offset starts where the previous one ended if that
+// was in the same file (<previous_end>, which is updated), else at its start.
+fn segment_range(
+    url: &str,
+    range: Option<&m3u8_rs::ByteRange>,
+    previous_end: &mut Option<(String, u64)>,
+) -> Option<(u64, u64)> {
+    let range = range.map(|range| {
+        let offset = range.offset.unwrap_or(match previous_end {
+            Some((previous_url, end)) if previous_url == url => *end,
+            _ => 0,
+        });
+        (offset, range.length)
+    });
+
+    *previous_end = range.map(|(offset, length)| (url.to_string(), offset + length));
+    range
+}
+
+fn fetch_segment(
+    agent: &ureq::Agent,
+    segment: &Segment,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>> {
+    let fetch = || -> Result<Vec<u8>> {
+        let mut request = agent.get(&segment.url);
+        if let Some((offset, length)) = segment.range {
+            request = request.set(
+                "Range",
+                &format!("bytes={}-{}", offset, offset + length - 1),
+            );
+        }
+
+        let response = request.call()?;
+        let partial = response.status() == 206;
+
+        let mut data = vec![];
+        options
+            .rate_limit
+            .limit(response.into_reader())
+            .read_to_end(&mut data)?;
+
+        // A server which ignores the Range header sends the whole file:
+        match segment.range {
+            Some((offset, length)) if !partial => data
+                .get(offset as usize..(offset + length) as usize)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| anyhow!("The segment {} is too short.", segment.url)),
+            _ => Ok(data),
+        }
+    };
+
+    // Retry failed segments individually:
+    let what = format!("Fetching the segment {}", segment.url);
+    let data = with_retries(options.fragment_retries, options.verbose, &what, fetch)
+        .with_context(|| format!("Could not fetch the segment {}", segment.url))?;
+    segment.decrypt(data)
+}
+
+// Fetches <segments> with <options.concurrent_fragments> threads and
+// passes them to <write> in their original order.
+fn fetch_segments(
+    agent: &ureq::Agent,
+    segments: &[Segment],
+    options: &DownloadOptions,
+    mut write: impl FnMut(Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let workers = options.concurrent_fragments.clamp(1, segments.len().max(1));
+
+    // Don't let the workers run too far ahead of the writer, or
+    // all of the video ends up in the memory:
+    let window = workers * 2;
+
+    let next_index = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let written = (Mutex::new(0usize), Condvar::new());
+    let (tx, rx) = mpsc::channel::<(usize, Result<Vec<u8>>)>();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let (next_index, failed, written) = (&next_index, &failed, &written);
+
+            scope.spawn(move || loop {
+                let index = next_index.fetch_add(1, Ordering::SeqCst);
+                if index >= segments.len() {
+                    break;
+                }
+
+                let mut done = written.0.lock().unwrap();
+                while index >= *done + window && !failed.load(Ordering::SeqCst) {
+                    done = written.1.wait(done).unwrap();
+                }
+                drop(done);
+
+                if failed.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if tx
+                    .send((index, fetch_segment(agent, &segments[index], options)))
+                    .is_err()
+                {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut write_in_order = || -> Result<()> {
+            let mut pending = BTreeMap::new();
+            let mut expected = 0;
+
+            for (index, data) in &rx {
+                pending.insert(index, data?);
+
+                while let Some(data) = pending.remove(&expected) {
+                    write(data)?;
+                    expected += 1;
+
+                    *written.0.lock().unwrap() = expected;
+                    written.1.notify_all();
+                }
+            }
+
+            Ok(())
+        };
+
+        let result = write_in_order();
+        if result.is_err() {
+            // Stop the workers. (With the lock held, or a worker could miss
+            // the notification between checking <failed> and waiting.)
+            let _done = written.0.lock().unwrap();
+            failed.store(true, Ordering::SeqCst);
+            written.1.notify_all();
+        }
+        result
+    })
+}
+
+// Resolves an URI from a playlist against the URL of the playlist (RFC 3986).
+// CDNs often sign the playlist URL with query parameters which they expect
+// on the segments as well, so these are carried over to the same host.
+fn resolve_uri(base: &Url, uri: &str) -> Result<String> {
+    let mut url = base
+        .join(uri)
+        .with_context(|| format!("Invalid URI {} in the playlist {}", uri, base))?;
+
+    if let Some(base_query) = base.query().filter(|_| url.origin() == base.origin()) {
+        let query = url.query().unwrap_or("").to_string();
+        let keys: Vec<&str> = query
+            .split('&')
+            .map(|pair| pair.split('=').next().unwrap_or(""))
+            .collect();
+        let missing: Vec<&str> = base_query
+            .split('&')
+            .filter(|pair| !keys.contains(&pair.split('=').next().unwrap_or("")))
+            .collect();
+
+        if !missing.is_empty() {
+            let mut pairs: Vec<&str> = query.split('&').filter(|pair| !pair.is_empty()).collect();
+            pairs.extend(missing);
+            url.set_query(Some(&pairs.join("&")));
+        }
+    }
+
+    Ok(url.to_string())
+}
+
+// returns the playlist at <url> and the URL to resolve its URIs against,
+// which differs from <url> if the server redirected the request.
+fn fetch_playlist(
+    agent: &ureq::Agent,
+    url: &Url,
+    retries: usize,
+    verbose: bool,
+) -> Result<(Playlist, Url)> {
+    let what = format!("Fetching the playlist {}", url);
+    let (playlist_text, base) = with_retries(retries, verbose, &what, || {
+        let response = agent.get(url.as_str()).call()?;
+        let base = Url::parse(response.get_url())?;
+        Ok((response.into_string()?, base))
+    })?;
+
+    let playlist = m3u8_rs::parse_playlist(playlist_text.as_bytes())
+        .finish()
+        .map(|playlist| playlist.1)
+        .map_err(|_| anyhow!("Could not parse the playlist {}.", url))?;
+
+    Ok((playlist, base))
+}
+
+// returns one format per variant of a master playlist, sorted by their bandwidth.
+// Their URLs are the ones of the variant playlists.
+fn variant_formats(master: &MasterPlaylist, base: &Url) -> Result<Vec<VideoFormat>> {
+    let mut formats: Vec<VideoFormat> = master
+        .variants
+        .iter()
+        .filter(|variant| !variant.is_i_frame)
+        .map(|variant| {
+            let (vcodec, acodec) = variant.codecs.as_deref().map_or((None, None), split_codecs);
+            let height = variant.resolution.map(|r| r.height);
+
+            Ok(VideoFormat {
+                id: match height {
+                    Some(height) => format!("hls-{}p", height),
+                    None => format!("hls-{}", variant.bandwidth / 1000),
+                },
+                url: resolve_uri(base, &variant.uri)?,
+                ext: "ts".to_string(),
+                is_playlist: true,
+                width: variant.resolution.map(|r| r.width),
+                height,
+                vcodec,
+                acodec,
+                bitrate: Some(variant.bandwidth / 1000),
+                ..Default::default()
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    // Variants of the same height (e.g. with different audio groups)
+    // are told apart by their bitrate:
+    let ids: Vec<String> = formats.iter().map(|format| format.id.clone()).collect();
+    for format in &mut formats {
+        if ids.iter().filter(|id| **id == format.id).count() > 1 {
+            format.id = format!("{}-{}", format.id, format.bitrate.unwrap_or(0));
+        }
+    }
+
+    formats.sort_by_key(|format| format.bitrate);
+    Ok(formats)
+}
+
+// returns the variants of the playlist at <url> as formats, or a single
+// "hls" format if it is a media playlist already.
+//
+// Like the formats of DASH manifests, they have the URL of the master
+// playlist with the format ID as fragment, e.g. ".../master.m3u8#hls-720p":
+// the master playlist knows which audio rendition belongs to a variant.
+pub fn playlist_formats(ctx: &HandlerContext, url: &str) -> Result<Vec<VideoFormat>> {
+    let agent = ctx.agent(url)?;
+    let url = Url::parse(url)?;
+
+    let (Playlist::MasterPlaylist(master), base) =
+        fetch_playlist(&agent, &url, ctx.retries, ctx.verbose)?
+    else {
+        return Ok(vec![VideoFormat {
+            id: "hls".to_string(),
+            url: url.to_string(),
+            ext: "ts".to_string(),
+            is_playlist: true,
+            ..Default::default()
+        }]);
+    };
+
+    let mut formats = variant_formats(&master, &base)?;
+    for format in &mut formats {
+        let mut format_url = url.clone();
+        format_url.set_fragment(Some(&format.id));
+        format.url = format_url.to_string();
+    }
+    Ok(formats)
+}
+
+// fMP4 segments make an MP4 file rather than an MPEG-TS one:
+fn output_filename(filename: &str, playlist: &MediaPlaylist) -> String {
+    match playlist
+        .segments
+        .iter()
+        .any(|segment| segment.map.is_some())
+    {
+        true => Path::new(filename)
+            .with_extension("mp4")
+            .to_string_lossy()
+            .to_string(),
+        false => filename.to_string(),
+    }
+}
+
+// Downloads the playlist at <url> into <filename> and returns the name
+// of the file, which gets the extension "mp4" for fMP4 playlists.
+pub fn download_from_playlist(
+    agent: &ureq::Agent,
+    url: &str,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<String> {
     // Download the playlist file into the temporary directory:
-    if verbose {
+    if options.verbose {
         println!("Found a playlist. Fetching ...");
     }
 
     let mut url = Url::parse(url)?;
-    let mut agent = ureq::agent();
+    let variant_id = url.fragment().map(str::to_string);
+    url.set_fragment(None);
 
-    if let Some(env_proxy) = env_proxy::for_url(&url).host_port() {
-        // Use a proxy:
-        let proxy = ureq::Proxy::new(format!("{}:{}", env_proxy.0, env_proxy.1));
-        agent = ureq::AgentBuilder::new().proxy(proxy.unwrap()).build();
+    let (master, base) = match fetch_playlist(agent, &url, options.retries, options.verbose)? {
+        (Playlist::MediaPlaylist(playlist), base) => {
+            let filename = output_filename(filename, &playlist);
+            download_media_playlist(agent, &url, &base, &playlist, &filename, options)?;
+            return Ok(filename);
+        }
+        (Playlist::MasterPlaylist(master), base) => (master, base),
+    };
+
+    // A master playlist only lists the variants of the video.
+    // Take the one the format stands for, or pick one of them:
+    let formats = variant_formats(&master, &base)?;
+    let format = match &variant_id {
+        Some(id) => formats
+            .iter()
+            .find(|format| format.id == *id)
+            .ok_or_else(|| anyhow!("The playlist {} has no variant {}.", url, id))?,
+        None => options
+            .format_selector
+            .select(&formats)
+            .or(formats.last())
+            .ok_or_else(|| anyhow!("The playlist {} has no variants.", url))?,
+    };
+
+    if options.verbose {
+        println!(
+            "Choosing the variant {} ({}).",
+            format.id,
+            format.resolution()
+        );
     }
 
-    let request = agent.get(url.as_str());
-    let playlist_text = request.call()?.into_string()?;
+    let variant_url = Url::parse(&format.url)?;
+    let (Playlist::MediaPlaylist(variant_playlist), variant_base) =
+        fetch_playlist(agent, &variant_url, options.retries, options.verbose)?
+    else {
+        return Err(anyhow!("The variant {} is no media playlist.", variant_url));
+    };
+    let filename = output_filename(filename, &variant_playlist);
+    download_media_playlist(
+        agent,
+        &variant_url,
+        &variant_base,
+        &variant_playlist,
+        &filename,
+        options,
+    )?;
+
+    // The audio might be a separate rendition:
+    let variant = master.variants.iter().find(|variant| {
+        !variant.is_i_frame && resolve_uri(&base, &variant.uri).ok() == Some(format.url.clone())
+    });
+    let audio_uri = variant
+        .and_then(|variant| variant.audio.as_ref())
+        .and_then(|group_id| {
+            let renditions: Vec<&AlternativeMedia> = master
+                .alternatives
+                .iter()
+                .filter(|media| {
+                    media.media_type == AlternativeMediaType::Audio
+                        && media.group_id == *group_id
+                        && media.uri.is_some()
+                })
+                .collect();
+
+            renditions
+                .iter()
+                .find(|media| media.default)
+                .or(renditions.first())
+                .and_then(|media| media.uri.clone())
+        });
+
+    if let Some(audio_uri) = audio_uri {
+        if options.verbose {
+            println!("Fetching the audio rendition ...");
+        }
+
+        let audio_url = Url::parse(&resolve_uri(&base, &audio_uri)?)?;
+        let (Playlist::MediaPlaylist(audio_playlist), audio_base) =
+            fetch_playlist(agent, &audio_url, options.retries, options.verbose)?
+        else {
+            return Err(anyhow!("The rendition {} is no media playlist.", audio_url));
+        };
+
+        let audio_filename = format!("{}.audio", filename);
+        download_media_playlist(
+            agent,
+            &audio_url,
+            &audio_base,
+            &audio_playlist,
+            &audio_filename,
+            options,
+        )?;
 
-    if verbose {
-        println!("Parsing ...");
+        mux_audio(&filename, &audio_filename, &options.ffmpeg)?;
     }
 
-    // Parse the playlist:
-    let playlist = m3u8_rs::parse_media_playlist(playlist_text.as_bytes())
-        .finish()
-        .unwrap();
+    Ok(filename)
+}
+
+// Puts the video from <filename> and the audio from <audio_filename>
+// together into <filename>. Both files are kept if that fails.
+pub(crate) fn mux_audio(filename: &str, audio_filename: &str, ffmpeg: &Ffmpeg) -> Result<()> {
+    let file = Path::new(filename);
+    let muxed = file.with_extension(format!(
+        "muxed.{}",
+        file.extension().unwrap_or_default().to_string_lossy()
+    ));
+    ffmpeg.merge(file, Path::new(audio_filename), &muxed)?;
+    fs::rename(&muxed, file)?;
+    fs::remove_file(audio_filename)?;
+    Ok(())
+}
 
-    // Grab and concatenate the segments from the playlist:
-    // let file = Path::new(&filename);
+// Downloads the media playlist from <url>. Its URIs are relative to <base>.
+fn download_media_playlist(
+    agent: &ureq::Agent,
+    url: &Url,
+    base: &Url,
+    playlist: &MediaPlaylist,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let segments = segments(agent, base, playlist, options)?;
+    download_segments(agent, url.as_str(), &segments, filename, options)
+}
 
+// Grabs and concatenates <segments> into <filename>.
+// <source> (e.g. the playlist URL) identifies them in the journal.
+pub(crate) fn download_segments(
+    agent: &ureq::Agent,
+    source: &str,
+    segments: &[Segment],
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let part = part_filename(filename);
     let mut dest = fs::OpenOptions::new()
         .create(true)
-        .append(true)
-        .open(filename)?;
+        .truncate(false)
+        .write(true)
+        .open(&part)?;
+
+    // Continue an interrupted download of the same playlist, else start over:
+    let written = dest.metadata()?.len();
+    let mut journal = match Journal::load(filename) {
+        Some(journal)
+            if journal.playlist_url == source
+                && journal.segment_count == segments.len()
+                && journal.byte_offset <= written =>
+        {
+            if options.verbose {
+                println!(
+                    "Resuming after segment {} of {}.",
+                    journal.next_index(),
+                    journal.segment_count
+                );
+            }
+            journal
+        }
+        _ => Journal::new(filename, source, segments.len()),
+    };
+
+    // Throw away anything after the last complete segment
+    // (e.g. a segment that was only written halfway):
+    dest.set_len(journal.byte_offset)?;
+    dest.seek(SeekFrom::Start(journal.byte_offset))?;
 
     // Display a progress bar:
-    let total_cnt = playlist.1.segments.len() as u64;
-    let pb = ProgressBar::new(total_cnt);
+    let total_cnt = segments.len() as u64;
+    let pb = options.progress.add(ProgressBar::new(total_cnt));
     pb.set_style(
         ProgressStyle::with_template(
             "{spinner:.green} [{elapsed_precise}] [{bar:40.green/blue}] {percent}%",
@@ -84,54 +797,239 @@ pub fn download_from_playlist(url: &str, filename: &str, verbose: bool) -> Resul
         .unwrap()
         .progress_chars("#>-"),
     );
+    pb.set_position(journal.next_index() as u64);
 
-    for segment in &playlist.1.segments {
-        // .m3u8 playlists are usually relative.
-        // Take the original path (from the playlist) and replace
-        // the playlist itself by the video (e.g):
-        //   playlist URL:  https://foo.bar/play/file.m3u8
-        //   playlist item: file1.ts
-        //   result:        https://foo.bar/play/file1.ts
-        url.path_segments_mut().unwrap().pop().push(&segment.uri);
+    let start = journal.next_index();
+    let result = fetch_segments(agent, &segments[start..], options, |data| {
+        dest.write_all(&data)?;
+        dest.flush()?;
 
-        let request = agent.get(url.as_str());
-        let mut source = request.call()?.into_reader();
-
-        // Note: As we opened the file for appending only,
-        // file concatenation happens automatically.
-        let _ = copy(&mut source, &mut dest)?;
+        // Remember the progress:
+        journal.last_completed_index = Some(journal.next_index());
+        journal.byte_offset += data.len() as u64;
+        journal.save()?;
 
         // Update the progress bar:
         pb.inc(1);
-    }
+        Ok(())
+    });
 
+    // Do not leave a half-drawn progress bar above an error:
     pb.finish_and_clear();
+    result?;
+
+    // The download is complete, there's nothing to resume anymore:
+    drop(dest);
+    fs::rename(&part, filename)?;
+    journal.remove()?;
 
     Ok(())
 }
 
-pub fn download(url: &str, filename: &str) -> Result<()> {
-    let url = Url::parse(url)?;
-    let mut agent = ureq::agent();
+// What identifies the file behind an URL, so an interrupted download
+// is only continued with the same file. Stored as <filename>.journal.
+#[derive(Debug, Clone, PartialEq)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    size: Option<u64>,
+}
 
-    if let Some(env_proxy) = env_proxy::for_url(&url).host_port() {
-        // Use a proxy:
-        let proxy = ureq::Proxy::new(format!("{}:{}", env_proxy.0, env_proxy.1));
-        agent = ureq::AgentBuilder::new().proxy(proxy.unwrap()).build();
+impl Validators {
+    fn from_response(response: &ureq::Response, size: Option<u64>) -> Self {
+        Self {
+            // Weak ETags (W/"...") cannot be used for ranges:
+            etag: response
+                .header("ETag")
+                .filter(|etag| !etag.starts_with("W/"))
+                .map(str::to_string),
+            last_modified: response.header("Last-Modified").map(str::to_string),
+            size,
+        }
     }
 
-    let resp = agent.get(url.as_str()).call()?;
+    fn from_json(validators: &Value) -> Self {
+        Self {
+            etag: validators["etag"].as_str().map(str::to_string),
+            last_modified: validators["last_modified"].as_str().map(str::to_string),
+            size: validators["size"].as_u64(),
+        }
+    }
 
-    // Find the video size:
-    let total_size = resp
-        .header("Content-Length")
-        .unwrap_or("0")
-        .parse::<u64>()?;
+    fn to_json(&self) -> Value {
+        json!({
+            "etag": self.etag,
+            "last_modified": self.last_modified,
+            "size": self.size,
+        })
+    }
 
-    let mut request = agent.get(url.as_str());
+    fn load(filename: &str) -> Option<Self> {
+        let journal: Value =
+            serde_json::from_str(&fs::read_to_string(Journal::path_for(filename)).ok()?).ok()?;
+
+        // A download in ranges has gaps in the file, it cannot be continued at its end:
+        match journal.get("ranges") {
+            None => Some(Self::from_json(&journal)),
+            Some(_) => None,
+        }
+    }
+
+    fn save(&self, filename: &str) -> Result<()> {
+        fs::write(Journal::path_for(filename), self.to_json().to_string())?;
+        Ok(())
+    }
+
+    // returns the value for an If-Range header, if there is a strong one.
+    fn if_range(&self) -> Option<&str> {
+        self.etag.as_deref().or(self.last_modified.as_deref())
+    }
+
+    // true, if <other> (from a later response) describes the same file.
+    fn matches(&self, other: &Validators) -> bool {
+        let same = |a: &Option<String>, b: &Option<String>| a.is_none() || b.is_none() || a == b;
+        same(&self.etag, &other.etag)
+            && same(&self.last_modified, &other.last_modified)
+            && (self.size.is_none() || other.size.is_none() || self.size == other.size)
+    }
+}
+
+// Parses a Content-Range header like "bytes 100-199/1000" (or "bytes */1000")
+// into the first byte, if any, and the total size, if known.
+fn content_range(response: &ureq::Response) -> Option<(Option<u64>, Option<u64>)> {
+    let (range, total) = response
+        .header("Content-Range")?
+        .trim()
+        .strip_prefix("bytes ")?
+        .split_once('/')?;
+
+    Some((
+        range
+            .split_once('-')
+            .and_then(|(start, _)| start.parse().ok()),
+        total.parse().ok(),
+    ))
+}
+
+// Splitting smaller files is not worth the additional requests:
+const MIN_RANGE_SIZE: u64 = 1024 * 1024;
+
+// How much a connection downloads before its progress is written to the journal:
+const RANGE_JOURNAL_INTERVAL: u64 = 1024 * 1024;
+
+// A part of a download in several connections:
+#[derive(Debug, Clone, PartialEq)]
+struct ByteRange {
+    start: u64,
+    // The last byte of the range.
+    end: u64,
+    // How many bytes of the range are in the file already.
+    done: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    // splits <size> bytes into (at most) <count> ranges.
+    fn split(size: u64, count: u64) -> Vec<Self> {
+        let count = count.clamp(1, size.div_ceil(MIN_RANGE_SIZE).max(1));
+        let len = size.div_ceil(count);
+
+        (0..count)
+            .map(|i| i * len)
+            .filter(|start| *start < size)
+            .map(|start| Self {
+                start,
+                end: (start + len).min(size) - 1,
+                done: 0,
+            })
+            .collect()
+    }
+}
+
+// The journal of a download in several connections: the validators
+// of the file and the progress of each range.
+fn load_ranges(filename: &str) -> Option<(Validators, Vec<ByteRange>)> {
+    let journal: Value =
+        serde_json::from_str(&fs::read_to_string(Journal::path_for(filename)).ok()?).ok()?;
+
+    let ranges = journal["ranges"]
+        .as_array()?
+        .iter()
+        .map(|range| {
+            Some(ByteRange {
+                start: range["start"].as_u64()?,
+                end: range["end"].as_u64()?,
+                done: range["done"].as_u64()?,
+            })
+        })
+        .collect::<Option<_>>()?;
+
+    Some((Validators::from_json(&journal), ranges))
+}
+
+fn save_ranges(filename: &str, validators: &Validators, ranges: &[ByteRange]) -> Result<()> {
+    let mut journal = validators.to_json();
+    journal["ranges"] = ranges
+        .iter()
+        .map(|range| json!({ "start": range.start, "end": range.end, "done": range.done }))
+        .collect();
+    fs::write(Journal::path_for(filename), journal.to_string())?;
+    Ok(())
+}
+
+// Downloads <url> into <filename> in <options.connections> byte ranges at the
+// same time. returns false (without downloading anything) if the server
+// cannot send ranges or does not tell the size of the file. (A single
+// connection may still work then, and it is retried as usual.)
+fn download_ranges(
+    agent: &ureq::Agent,
+    url: &Url,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<bool> {
+    let Ok(probe) = agent.get(url.as_str()).set("Range", "bytes=0-0").call() else {
+        return Ok(false);
+    };
+    let Some((_, Some(size))) = content_range(&probe).filter(|_| probe.status() == 206) else {
+        return Ok(false);
+    };
+
+    let validators = Validators::from_response(&probe, Some(size));
+    let ranges = match load_ranges(filename) {
+        // The file must not have changed in the meantime (which the
+        // size alone does not tell):
+        Some((saved, ranges))
+            if saved == validators
+                && validators.if_range().is_some()
+                && fs::metadata(part_filename(filename)).map_or(0, |m| m.len()) == size =>
+        {
+            if options.verbose {
+                println!("Resuming the download in {} ranges.", ranges.len());
+            }
+            ranges
+        }
+        _ => ByteRange::split(size, options.connections as u64),
+    };
+
+    if ranges.len() < 2 && ranges.iter().all(|range| range.done == 0) {
+        return Ok(false);
+    }
+
+    // Preallocate the file, the ranges are written where they belong:
+    let part = part_filename(filename);
+    fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&part)?
+        .set_len(size)?;
+    save_ranges(filename, &validators, &ranges)?;
 
     // Display a progress bar:
-    let pb = ProgressBar::new(total_size);
+    let pb = options.progress.add(ProgressBar::new(size));
     pb.set_style(
         ProgressStyle::with_template(
             "{spinner:.green} [{elapsed_precise}] [{bar:40.green/blue}] {percent}%",
@@ -139,34 +1037,696 @@ pub fn download(url: &str, filename: &str) -> Result<()> {
         .unwrap()
         .progress_chars("#>-"),
     );
+    pb.set_position(ranges.iter().map(|range| range.done).sum());
 
-    let file = Path::new(filename);
+    let progress = Mutex::new(ranges.clone());
+    let failed = AtomicBool::new(false);
+
+    let fetch_range = |i: usize, range: &ByteRange| -> Result<()> {
+        let from = range.start + range.done;
+        if from > range.end {
+            return Ok(());
+        }
 
-    if file.exists() {
-        // Continue the file:
-        let size = file.metadata()?.len() - 1;
-        // Override the range:
-        request = agent
+        let mut request = agent
             .get(url.as_str())
-            .set("Range", &format!("bytes={}-", size))
-            .to_owned();
-        pb.inc(size);
+            .set("Range", &format!("bytes={}-{}", from, range.end));
+        if let Some(if_range) = validators.if_range() {
+            request = request.set("If-Range", if_range);
+        }
+
+        let response = request.call()?;
+        if response.status() != 206
+            || content_range(&response).and_then(|(start, _)| start) != Some(from)
+        {
+            return Err(anyhow!(
+                "The server did not send the range {}-{} of {}.",
+                from,
+                range.end,
+                url
+            ));
+        }
+
+        let mut dest = fs::OpenOptions::new().write(true).open(&part)?;
+        dest.seek(SeekFrom::Start(from))?;
+
+        let mut source = DownloadProgress {
+            progress_bar: &pb,
+            inner: options
+                .rate_limit
+                .limit(response.into_reader().take(range.end + 1 - from)),
+        };
+        let mut buffer = vec![0; 64 * 1024];
+        let mut unsaved = 0;
+
+        loop {
+            if failed.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            let n = source.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            dest.write_all(&buffer[..n])?;
+
+            // Remember the progress every now and then:
+            unsaved += n as u64;
+            let mut ranges = progress.lock().unwrap();
+            ranges[i].done += n as u64;
+            if unsaved >= RANGE_JOURNAL_INTERVAL || ranges[i].done == ranges[i].len() {
+                dest.flush()?;
+                save_ranges(filename, &validators, &ranges)?;
+                unsaved = 0;
+            }
+        }
+
+        match progress.lock().unwrap()[i].done == range.len() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "The range {}-{} of {} stopped early, try again to continue it.",
+                    range.start, range.end, url
+                ),
+            )
+            .into()),
+        }
+    };
+
+    let result = thread::scope(|scope| -> Result<()> {
+        let connections: Vec<_> = ranges
+            .iter()
+            .enumerate()
+            .map(|(i, range)| {
+                let fetch_range = &fetch_range;
+                let failed = &failed;
+                scope.spawn(move || {
+                    fetch_range(i, range).inspect_err(|_| failed.store(true, Ordering::SeqCst))
+                })
+            })
+            .collect();
+
+        let mut result = Ok(());
+        for connection in connections {
+            let connection_result = connection
+                .join()
+                .map_err(|_| anyhow!("A download thread panicked."))?;
+            if result.is_ok() {
+                result = connection_result;
+            }
+        }
+        result
+    });
+
+    pb.finish_and_clear();
+    result.inspect_err(|_| {
+        // Keep what was downloaded:
+        let _ = save_ranges(filename, &validators, &progress.lock().unwrap());
+    })?;
+
+    fs::rename(&part, filename)?;
+    remove_partial(filename)?;
+    Ok(true)
+}
+
+// Downloads <url> into <filename>. An unfinished download of the same
+// file is continued if the server still has it and supports ranges,
+// otherwise it starts over.
+pub fn download(
+    agent: &ureq::Agent,
+    url: &str,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    // Each attempt continues where the previous one stopped:
+    let what = format!("Downloading {}", url);
+    with_retries(options.retries, options.verbose, &what, || {
+        download_file(agent, url, filename, options)
+    })
+}
+
+fn download_file(
+    agent: &ureq::Agent,
+    url: &str,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let url = Url::parse(url)?;
+    if options.connections > 1 && download_ranges(agent, &url, filename, options)? {
+        return Ok(());
     }
 
-    let resp = request.call()?;
-    let mut source = DownloadProgress {
-        progress_bar: &pb,
-        inner: resp.into_reader(),
+    let part = part_filename(filename);
+
+    // There is nothing to continue if the server did not say how to
+    // recognize the file the last time. (The size alone does not tell
+    // whether the file has changed.)
+    let existing = fs::metadata(&part).map_or(0, |metadata| metadata.len());
+    let saved =
+        Validators::load(filename).filter(|saved| existing > 0 && saved.if_range().is_some());
+
+    let mut request = agent.get(url.as_str());
+    if let Some(saved) = &saved {
+        // The ranged request is the probe: if the file has changed
+        // (If-Range), the server sends all of it instead (200).
+        request = request.set("Range", &format!("bytes={}-", existing));
+        if let Some(if_range) = saved.if_range() {
+            request = request.set("If-Range", if_range);
+        }
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        // The range starts at the end of the file - is it complete?
+        Err(ureq::Error::Status(416, response))
+            if saved.as_ref().and_then(|saved| saved.size) == Some(existing)
+                && content_range(&response).and_then(|(_, total)| total) == Some(existing) =>
+        {
+            fs::rename(&part, filename)?;
+            return remove_partial(filename);
+        }
+        Err(ureq::Error::Status(416, _)) => {
+            // Start over:
+            remove_partial(filename)?;
+            return download_file(agent, url.as_str(), filename, options);
+        }
+        Err(e) => return Err(e.into()),
     };
 
+    let content_length = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<u64>().ok());
+
+    let resume_from = match (&saved, response.status()) {
+        (Some(saved), 206) => match content_range(&response) {
+            Some((Some(start), total))
+                if start == existing
+                    && saved.matches(&Validators::from_response(&response, total)) =>
+            {
+                Some(start)
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+
+    if saved.is_some() && options.verbose {
+        match resume_from {
+            Some(start) => println!("Resuming the download at byte {}.", start),
+            None => println!("The download cannot be resumed, starting over."),
+        }
+    }
+
+    // A 206 response without a proper range cannot be used at all:
+    if saved.is_some() && resume_from.is_none() && response.status() == 206 {
+        remove_partial(filename)?;
+        return download_file(agent, url.as_str(), filename, options);
+    }
+
+    let start = resume_from.unwrap_or(0);
+    let total_size = content_length.map(|length| start + length);
+    Validators::from_response(&response, total_size).save(filename)?;
+
+    // Anything after <start> is thrown away (it is either sent again or
+    // belongs to a different file):
     let mut dest = fs::OpenOptions::new()
         .create(true)
-        .append(true)
-        .open(file)?;
+        .truncate(false)
+        .write(true)
+        .open(&part)?;
+    dest.set_len(start)?;
+    dest.seek(SeekFrom::Start(start))?;
+
+    // Display a progress bar:
+    let pb = options
+        .progress
+        .add(ProgressBar::new(total_size.unwrap_or(0)));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.green/blue}] {percent}%",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+    pb.set_position(start);
 
-    let _ = copy(&mut source, &mut dest)?;
+    let mut source = DownloadProgress {
+        progress_bar: &pb,
+        inner: options.rate_limit.limit(response.into_reader()),
+    };
 
+    let copied = copy(&mut source, &mut dest);
     pb.finish_and_clear();
+    drop(dest);
+    let written = start + copied?;
 
-    Ok(())
+    if let Some(total_size) = total_size.filter(|total_size| written != *total_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "The download of {} stopped after {} of {} bytes, try again to continue it.",
+                filename, written, total_size
+            ),
+        )
+        .into());
+    }
+
+    fs::rename(&part, filename)?;
+    remove_partial(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncryptMut;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+
+    const KEY1: [u8; 16] = [1; 16];
+    const KEY2: [u8; 16] = [2; 16];
+
+    // Serves what <respond> returns for each path, returns the base URL
+    // and the paths which were requested so far.
+    fn serve(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requested = Arc::new(Mutex::new(vec![]));
+
+        let paths = requested.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 1024];
+                let n = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]);
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                paths.lock().unwrap().push(path.to_string());
+
+                let body = respond(path);
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .as_bytes(),
+                );
+                let _ = stream.write_all(&body);
+            }
+        });
+
+        (base, requested)
+    }
+
+    // Serves KEY1 as /k1.key and KEY2 as /k2.key, returns the base URL.
+    fn serve_keys() -> Url {
+        serve(|path| match path {
+            "/k1.key" => KEY1.to_vec(),
+            _ => KEY2.to_vec(),
+        })
+        .0
+    }
+
+    fn parse(playlist: &str) -> MediaPlaylist {
+        m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap()
+    }
+
+    fn encrypt(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        Aes128CbcEnc::new(key.into(), iv.into()).encrypt_padded_vec_mut::<Pkcs7>(data)
+    }
+
+    fn segments_of(playlist: &str) -> Vec<Segment> {
+        let base = serve_keys();
+        segments(
+            &ureq::agent(),
+            &base,
+            &parse(playlist),
+            &DownloadOptions::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn explicit_iv() {
+        let segments = segments_of(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"k1.key\",IV=0x0000000000000000000000000000002A\n\
+             #EXTINF:10,\ns0.ts\n#EXTINF:10,\ns1.ts\n#EXT-X-ENDLIST\n",
+        );
+
+        let iv = 42u128.to_be_bytes();
+        assert_eq!(segments.len(), 2);
+        assert!(segments.iter().all(|s| s.encryption == Some((KEY1, iv))));
+
+        let plain = b"some MPEG-TS data".to_vec();
+        let decrypted = segments[0].decrypt(encrypt(&KEY1, &iv, &plain)).unwrap();
+        assert_eq!(decrypted, plain);
+    }
+
+    #[test]
+    fn iv_from_media_sequence() {
+        let segments = segments_of(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-MEDIA-SEQUENCE:7\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"k1.key\"\n\
+             #EXTINF:10,\ns0.ts\n#EXTINF:10,\ns1.ts\n#EXT-X-ENDLIST\n",
+        );
+
+        assert_eq!(segments[0].encryption, Some((KEY1, 7u128.to_be_bytes())));
+        assert_eq!(segments[1].encryption, Some((KEY1, 8u128.to_be_bytes())));
+
+        let plain = vec![0x47; 188];
+        let encrypted = encrypt(&KEY1, &8u128.to_be_bytes(), &plain);
+        assert_eq!(segments[1].decrypt(encrypted.clone()).unwrap(), plain);
+        assert_ne!(segments[0].decrypt(encrypted).ok(), Some(plain));
+    }
+
+    #[test]
+    fn key_rotation_and_method_none() {
+        let segments = segments_of(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"k1.key\"\n#EXTINF:10,\ns0.ts\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"k2.key\"\n#EXTINF:10,\ns1.ts\n\
+             #EXTINF:10,\ns2.ts\n\
+             #EXT-X-KEY:METHOD=NONE\n#EXTINF:10,\ns3.ts\n#EXT-X-ENDLIST\n",
+        );
+
+        let keys: Vec<_> = segments
+            .iter()
+            .map(|s| s.encryption.map(|(key, _)| key))
+            .collect();
+        assert_eq!(keys, [Some(KEY1), Some(KEY2), Some(KEY2), None]);
+        assert!(segments[3].url.ends_with("/s3.ts"));
+    }
+
+    #[test]
+    fn plaintext_init_section() {
+        let segments = segments_of(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-VERSION:6\n\
+             #EXT-X-MAP:URI=\"init.mp4\"\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"k1.key\",IV=0x01\n\
+             #EXTINF:10,\ns0.m4s\n#EXT-X-ENDLIST\n",
+        );
+
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].url.ends_with("/init.mp4"));
+
+        // Whether the section is encrypted depends on the tag order:
+        let init = b"\0\0\0\x18ftypisom\0\0\0\0isomiso6".to_vec();
+        assert_eq!(segments[0].decrypt(init.clone()).unwrap(), init);
+        let iv = 1u128.to_be_bytes();
+        assert_eq!(
+            segments[0].decrypt(encrypt(&KEY1, &iv, &init)).unwrap(),
+            init
+        );
+    }
+
+    fn response(content_range: Option<&str>) -> ureq::Response {
+        let header = content_range
+            .map(|value| format!("Content-Range: {}\r\n", value))
+            .unwrap_or_default();
+        format!("HTTP/1.1 206 Partial Content\r\n{}\r\n", header)
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn content_ranges() {
+        assert_eq!(
+            content_range(&response(Some("bytes 0-99/1000"))),
+            Some((Some(0), Some(1000)))
+        );
+        assert_eq!(
+            content_range(&response(Some("bytes 100-199/*"))),
+            Some((Some(100), None))
+        );
+        assert_eq!(
+            content_range(&response(Some("bytes */1000"))),
+            Some((None, Some(1000)))
+        );
+        assert_eq!(content_range(&response(Some("items 0-9/10"))), None);
+        assert_eq!(content_range(&response(Some("bytes 0-99"))), None);
+        assert_eq!(content_range(&response(None)), None);
+    }
+
+    // true, if <ranges> cover all of <size> bytes without gaps or overlaps.
+    fn covers(ranges: &[ByteRange], size: u64) -> bool {
+        ranges.first().map(|range| range.start) == Some(0)
+            && ranges.last().map(|range| range.end) == Some(size - 1)
+            && ranges
+                .windows(2)
+                .all(|pair| pair[1].start == pair[0].end + 1)
+            && ranges.iter().map(ByteRange::len).sum::<u64>() == size
+    }
+
+    #[test]
+    fn byte_ranges() {
+        // Small files are not split:
+        assert_eq!(
+            ByteRange::split(1000, 4),
+            vec![ByteRange {
+                start: 0,
+                end: 999,
+                done: 0
+            }]
+        );
+
+        // No more ranges than MiBs:
+        let ranges = ByteRange::split(3 * MIN_RANGE_SIZE, 8);
+        assert_eq!(ranges.len(), 3);
+        assert!(covers(&ranges, 3 * MIN_RANGE_SIZE));
+
+        // At least one range:
+        assert_eq!(ByteRange::split(10 * MIN_RANGE_SIZE, 0).len(), 1);
+
+        // Uneven sizes:
+        let size = 10 * MIN_RANGE_SIZE + 7;
+        let ranges = ByteRange::split(size, 4);
+        assert_eq!(ranges.len(), 4);
+        assert!(covers(&ranges, size));
+        assert!(ranges.iter().all(|range| range.done == 0));
+    }
+
+    // returns a file name in the temporary directory which is unique to this test run.
+    fn temp_filename(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("yaydl-{}-{}.ts", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // Serves three segments ("/s0" is "seg0" and so on) and returns them.
+    fn serve_segments() -> (Vec<Segment>, Arc<Mutex<Vec<String>>>) {
+        let (base, requested) = serve(|path| format!("seg{}", &path[2..]).into_bytes());
+        let segments = (0..3)
+            .map(|i| Segment {
+                url: base.join(&format!("s{}", i)).unwrap().to_string(),
+                range: None,
+                encryption: None,
+            })
+            .collect();
+        (segments, requested)
+    }
+
+    #[test]
+    fn journals() {
+        let filename = temp_filename("journal");
+        assert!(Journal::load(&filename).is_none());
+
+        let mut journal = Journal::new(&filename, "https://example.com/a.m3u8", 10);
+        assert_eq!(journal.next_index(), 0);
+        journal.last_completed_index = Some(3);
+        journal.byte_offset = 1234;
+        journal.save().unwrap();
+
+        let loaded = Journal::load(&filename).unwrap();
+        assert_eq!(loaded.playlist_url, "https://example.com/a.m3u8");
+        assert_eq!(loaded.segment_count, 10);
+        assert_eq!(loaded.last_completed_index, Some(3));
+        assert_eq!(loaded.byte_offset, 1234);
+        assert_eq!(loaded.next_index(), 4);
+
+        loaded.remove().unwrap();
+        assert!(Journal::load(&filename).is_none());
+
+        fs::write(Journal::path_for(&filename), "{ not json").unwrap();
+        assert!(Journal::load(&filename).is_none());
+        journal.remove().unwrap();
+    }
+
+    #[test]
+    fn resumes_after_the_last_complete_segment() {
+        let filename = temp_filename("resume");
+        let (segments, requested) = serve_segments();
+
+        // The second segment was only written halfway:
+        fs::write(part_filename(&filename), "seg0se").unwrap();
+        let mut journal = Journal::new(&filename, "playlist", segments.len());
+        journal.last_completed_index = Some(0);
+        journal.byte_offset = 4;
+        journal.save().unwrap();
+
+        let options = DownloadOptions::default();
+        download_segments(&ureq::agent(), "playlist", &segments, &filename, &options).unwrap();
+
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "seg0seg1seg2");
+        assert_eq!(*requested.lock().unwrap(), ["/s1", "/s2"]);
+        assert!(!Journal::path_for(&filename).exists());
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn starts_over_for_another_playlist() {
+        let filename = temp_filename("other");
+        let (segments, requested) = serve_segments();
+
+        fs::write(part_filename(&filename), "old data").unwrap();
+        let mut journal = Journal::new(&filename, "another playlist", segments.len());
+        journal.last_completed_index = Some(1);
+        journal.byte_offset = 8;
+        journal.save().unwrap();
+
+        let options = DownloadOptions::default();
+        download_segments(&ureq::agent(), "playlist", &segments, &filename, &options).unwrap();
+
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "seg0seg1seg2");
+        assert_eq!(requested.lock().unwrap().len(), 3);
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn starts_over_without_the_journaled_data() {
+        let filename = temp_filename("short");
+        let (segments, requested) = serve_segments();
+
+        // The journal promises more than the file has:
+        fs::write(part_filename(&filename), "seg0").unwrap();
+        let mut journal = Journal::new(&filename, "playlist", segments.len());
+        journal.last_completed_index = Some(1);
+        journal.byte_offset = 8;
+        journal.save().unwrap();
+
+        let options = DownloadOptions::default();
+        download_segments(&ureq::agent(), "playlist", &segments, &filename, &options).unwrap();
+
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "seg0seg1seg2");
+        assert_eq!(requested.lock().unwrap().len(), 3);
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn uris() {
+        let signed =
+            Url::parse("https://cdn.example.com/videos/hls/index.m3u8?token=abc&exp=1").unwrap();
+        let plain = Url::parse("https://cdn.example.com/videos/hls/index.m3u8").unwrap();
+
+        for (base, uri, expected) in [
+            (
+                &signed,
+                "s0.ts",
+                "https://cdn.example.com/videos/hls/s0.ts?token=abc&exp=1",
+            ),
+            (
+                &signed,
+                "../audio/en.m3u8",
+                "https://cdn.example.com/videos/audio/en.m3u8?token=abc&exp=1",
+            ),
+            (
+                &signed,
+                "./a/../../b/./s0.ts",
+                "https://cdn.example.com/videos/b/s0.ts?token=abc&exp=1",
+            ),
+            (
+                &signed,
+                "/s0.ts",
+                "https://cdn.example.com/s0.ts?token=abc&exp=1",
+            ),
+            // Parameters of the segment itself win:
+            (
+                &signed,
+                "s0.ts?token=own",
+                "https://cdn.example.com/videos/hls/s0.ts?token=own&exp=1",
+            ),
+            (
+                &signed,
+                "//cdn.example.com/s0.ts",
+                "https://cdn.example.com/s0.ts?token=abc&exp=1",
+            ),
+            // Other origins do not get the token:
+            (
+                &signed,
+                "//other.example.com/s0.ts",
+                "https://other.example.com/s0.ts",
+            ),
+            (
+                &signed,
+                "https://other.example.com/s0.ts?x=1",
+                "https://other.example.com/s0.ts?x=1",
+            ),
+            (
+                &signed,
+                "http://cdn.example.com/s0.ts",
+                "http://cdn.example.com/s0.ts",
+            ),
+            (
+                &plain,
+                "s0.ts?x=1",
+                "https://cdn.example.com/videos/hls/s0.ts?x=1",
+            ),
+            (&plain, "../s0.ts", "https://cdn.example.com/videos/s0.ts"),
+        ] {
+            assert_eq!(resolve_uri(base, uri).unwrap(), expected, "{}", uri);
+        }
+
+        assert!(resolve_uri(&signed, "http://[::1").is_err());
+    }
+
+    #[test]
+    fn segment_ranges() {
+        let range = |length, offset| m3u8_rs::ByteRange { length, offset };
+        let mut previous_end = None;
+        let mut next = |url: &str, byte_range: Option<m3u8_rs::ByteRange>| {
+            segment_range(url, byte_range.as_ref(), &mut previous_end)
+        };
+
+        assert_eq!(next("a.ts", Some(range(100, None))), Some((0, 100)));
+        assert_eq!(next("a.ts", Some(range(50, None))), Some((100, 50)));
+        assert_eq!(next("a.ts", Some(range(10, Some(500)))), Some((500, 10)));
+        assert_eq!(next("a.ts", Some(range(10, None))), Some((510, 10)));
+        // Another file starts at its beginning again,
+        assert_eq!(next("b.ts", Some(range(20, None))), Some((0, 20)));
+        // and so does the same file after a segment without a range:
+        assert_eq!(next("b.ts", None), None);
+        assert_eq!(next("b.ts", Some(range(20, None))), Some((0, 20)));
+    }
+
+    #[test]
+    fn byte_ranges_of_playlists() {
+        let segments = segments_of(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n\
+             #EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"720@0\"\n\
+             #EXT-X-BYTERANGE:1000@720\n#EXTINF:10,\nmain.mp4\n\
+             #EXT-X-BYTERANGE:2000\n#EXTINF:10,\nmain.mp4\n\
+             #EXT-X-BYTERANGE:3000\n#EXTINF:10,\nmain.mp4\n#EXT-X-ENDLIST\n",
+        );
+        let ranges: Vec<_> = segments.iter().map(|segment| segment.range).collect();
+        assert_eq!(
+            ranges,
+            [
+                Some((0, 720)),
+                Some((720, 1000)),
+                Some((1720, 2000)),
+                Some((3720, 3000)),
+            ]
+        );
+
+        // An initialization section without an offset starts at 0:
+        let segments = segments_of(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n\
+             #EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720\"\n\
+             #EXTINF:10,\ns0.mp4\n#EXT-X-ENDLIST\n",
+        );
+        assert_eq!(segments[0].range, Some((0, 720)));
+        assert_eq!(segments[1].range, None);
+    }
 }
@@ -0,0 +1,568 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - downloader.rs file -
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::definitions::{HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
+use crate::download::DownloadOptions;
+use crate::ffmpeg::{AudioQuality, Embedding, Ffmpeg};
+use crate::http::HttpClient;
+use crate::processor::{
+    process_video, InputOutputPaths, PostProcessingStep, TargetFile, VideoProcessingResult,
+};
+use crate::ratelimit::RateLimiter;
+use crate::retry::DEFAULT_RETRIES;
+use crate::sanitize::Sanitizer;
+use crate::selector::FormatSelector;
+use crate::template::DEFAULT_TEMPLATE;
+
+/// The programmatic entry point of yaydl.
+///
+/// Use `Downloader::builder()` to configure one, then call `download()`
+/// for every URL you want to fetch.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    pub(crate) output_dir: Option<PathBuf>,
+    pub(crate) output_template: String,
+    pub(crate) sanitizer: Sanitizer,
+    pub(crate) overwrites: Overwrites,
+    pub(crate) only_audio: bool,
+    pub(crate) audio_format: String,
+    pub(crate) audio_quality: Option<AudioQuality>,
+    pub(crate) keep_temp_file: bool,
+    pub(crate) embed_metadata: bool,
+    pub(crate) embed_thumbnail: bool,
+    pub(crate) verbose: bool,
+    pub(crate) format: Option<String>,
+    pub(crate) download_options: DownloadOptions,
+    pub(crate) context: HandlerContext,
+}
+
+/// What to do if a file to download exists already.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Overwrites {
+    /// Pick another name, e.g. "Title (1).mp4".
+    #[default]
+    Rename,
+    /// Keep the existing file and skip the download.
+    Never,
+    /// Replace the existing file.
+    Always,
+}
+
+/// Builds a `Downloader`.
+#[derive(Debug, Clone)]
+pub struct DownloaderBuilder {
+    downloader: Downloader,
+}
+
+/// What happened to a URL passed to `Downloader::download()`.
+#[derive(Debug)]
+pub enum DownloadOutcome {
+    /// The video was downloaded (and converted, if required).
+    Downloaded(Box<DownloadedVideo>),
+    /// No site definition can handle the URL.
+    UnsupportedUrl,
+    /// The site (by its display name) needs a web driver, but no port was set.
+    WebDriverRequired(String),
+    /// The site could not find the video.
+    VideoNotFound,
+    /// The site could not find the video title.
+    TitleNotFound,
+    /// None of the available formats matches the format selector.
+    FormatNotFound,
+    /// The file (at the given path) exists already and `Overwrites::Never` is set.
+    AlreadyDownloaded(PathBuf),
+}
+
+/// A successfully downloaded video.
+#[derive(Debug, Clone)]
+pub struct DownloadedVideo {
+    /// The display name of the site the video was fetched from.
+    pub site: String,
+    /// Everything the site told about the video.
+    pub metadata: VideoMetadata,
+    /// The path of the resulting file.
+    pub path: PathBuf,
+}
+
+impl Default for DownloaderBuilder {
+    fn default() -> Self {
+        Self {
+            downloader: Downloader {
+                output_dir: None,
+                output_template: DEFAULT_TEMPLATE.to_string(),
+                sanitizer: Sanitizer::default(),
+                overwrites: Overwrites::default(),
+                only_audio: false,
+                audio_format: "mp3".to_string(),
+                audio_quality: None,
+                keep_temp_file: false,
+                embed_metadata: false,
+                embed_thumbnail: false,
+                verbose: false,
+                format: None,
+                download_options: DownloadOptions::default(),
+                context: HandlerContext {
+                    retries: DEFAULT_RETRIES,
+                    ..Default::default()
+                },
+            },
+        }
+    }
+}
+
+impl DownloaderBuilder {
+    /// Sets the directory to write the files to (default: the current one).
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.downloader.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets how the files are named (relative to `output_dir`), e.g.
+    /// "%(uploader)s/%(upload_date)s - %(title)s [%(id)s].%(ext)s".
+    ///
+    /// Known fields: id, title, uploader, upload_date, duration, ext,
+    /// format_id, width, height and resolution. Missing directories are
+    /// created. Defaults to "%(title)s.%(ext)s".
+    pub fn output_template(mut self, template: impl Into<String>) -> Self {
+        self.downloader.output_template = template.into();
+        self
+    }
+
+    /// Sets what to do if a file exists already (default: `Overwrites::Rename`).
+    pub fn overwrites(mut self, overwrites: Overwrites) -> Self {
+        self.downloader.overwrites = overwrites;
+        self
+    }
+
+    /// Only uses ASCII letters, digits, '_', '-' and '.' for the
+    /// values in file names, e.g. "Cafe_au_lait" for "Café au lait".
+    pub fn restrict_filenames(mut self, value: bool) -> Self {
+        self.downloader.sanitizer.restrict = value;
+        self
+    }
+
+    /// Makes file names valid on Windows, even on other systems
+    /// (always on for Windows itself).
+    pub fn windows_filenames(mut self, value: bool) -> Self {
+        self.downloader.sanitizer.windows = value;
+        self
+    }
+
+    /// Only keeps the audio stream.
+    pub fn only_audio(mut self, value: bool) -> Self {
+        self.downloader.only_audio = value;
+        self
+    }
+
+    /// Sets the target audio format for `only_audio`: "mp3", "aac", "opus",
+    /// "vorbis", "flac", "wav" or any other file extension ffmpeg knows.
+    pub fn audio_format(mut self, format: impl Into<String>) -> Self {
+        self.downloader.audio_format = format.into();
+        self
+    }
+
+    /// Sets the quality of converted audio (default: the encoder's default).
+    pub fn audio_quality(mut self, quality: AudioQuality) -> Self {
+        self.downloader.audio_quality = Some(quality);
+        self
+    }
+
+    /// Keeps all downloaded data: the separate video and audio streams
+    /// after merging them, the file before a conversion and the thumbnail.
+    pub fn keep_temp_file(mut self, value: bool) -> Self {
+        self.downloader.keep_temp_file = value;
+        self
+    }
+
+    /// Writes the title, the uploader, the date, the description and
+    /// the chapters of the video into the resulting file.
+    pub fn embed_metadata(mut self, value: bool) -> Self {
+        self.downloader.embed_metadata = value;
+        self
+    }
+
+    /// Writes the thumbnail of the video into the resulting file as its cover art.
+    pub fn embed_thumbnail(mut self, value: bool) -> Self {
+        self.downloader.embed_thumbnail = value;
+        self
+    }
+
+    /// Talks more while the URL is processed.
+    pub fn verbose(mut self, value: bool) -> Self {
+        self.downloader.verbose = value;
+        self.downloader.download_options.verbose = value;
+        self.downloader.context.verbose = value;
+        self
+    }
+
+    /// Sets the format selector, e.g. "best[height<=720]" or "bestaudio/best".
+    ///
+    /// Defaults to "bestvideo+bestaudio/best" (or "bestaudio/best" with `only_audio`).
+    pub fn format(mut self, selector: impl Into<String>) -> Self {
+        self.downloader.format = Some(selector.into());
+        self
+    }
+
+    /// Sets how many playlist segments are downloaded at the same time.
+    pub fn concurrent_fragments(mut self, count: usize) -> Self {
+        self.downloader.download_options.concurrent_fragments = count.max(1);
+        self
+    }
+
+    /// Sets how many connections download a single file at the same time.
+    /// Only used for files (not playlists) whose server supports ranges.
+    pub fn connections(mut self, count: usize) -> Self {
+        self.downloader.download_options.connections = count.max(1);
+        self
+    }
+
+    /// Sets how often a failed page fetch or download is retried (default: 3).
+    /// Errors which would only happen again (e.g. "404 Not Found") are not.
+    pub fn retries(mut self, count: usize) -> Self {
+        self.downloader.context.retries = count;
+        self.downloader.download_options.retries = count;
+        self
+    }
+
+    /// Sets how often a failed segment of a playlist is retried (default: 3).
+    pub fn fragment_retries(mut self, count: usize) -> Self {
+        self.downloader.download_options.fragment_retries = count;
+        self
+    }
+
+    /// Caps the download speed at <bytes_per_second> (0 for no limit).
+    /// The limit applies to all connections and segments together.
+    pub fn limit_rate(mut self, bytes_per_second: u64) -> Self {
+        self.downloader.download_options.rate_limit = RateLimiter::new(bytes_per_second);
+        self
+    }
+
+    /// Sets the ffmpeg binary to use instead of the one from the PATH.
+    pub fn ffmpeg_location(mut self, path: impl Into<PathBuf>) -> Self {
+        self.downloader.download_options.ffmpeg = Ffmpeg::new(path);
+        self
+    }
+
+    /// Sets the port of the web driver (required for some sites).
+    pub fn webdriver_port(mut self, port: u16) -> Self {
+        self.downloader.context.webdriver_port = port;
+        self
+    }
+
+    /// Sets the Invidious instance to use for YouTube links.
+    pub fn invidious_instance(mut self, instance: impl Into<String>) -> Self {
+        self.downloader.context.invidious_instance = Some(instance.into());
+        self
+    }
+
+    /// Sets the HTTP client to send all requests with (proxy, timeouts, headers).
+    pub fn http_client(mut self, client: HttpClient) -> Self {
+        self.downloader.context.http = client;
+        self
+    }
+
+    /// Returns the Downloader, or an error if the format selector is invalid.
+    pub fn build(mut self) -> Result<Downloader> {
+        // Better fail now than after the first extraction:
+        let selector = match (&self.downloader.format, self.downloader.only_audio) {
+            (Some(selector), _) => selector.as_str(),
+            (None, true) => "bestaudio/best",
+            (None, false) => "bestvideo+bestaudio/best",
+        };
+        self.downloader.download_options.format_selector = FormatSelector::parse(selector)?;
+
+        Ok(self.downloader)
+    }
+}
+
+impl Downloader {
+    pub fn builder() -> DownloaderBuilder {
+        DownloaderBuilder::default()
+    }
+
+    /// Returns the site definition which can handle <url>, if any.
+    pub fn handler_for(&self, url: &str) -> Option<&'static dyn SiteDefinition> {
+        inventory::iter::<&dyn SiteDefinition>
+            .into_iter()
+            .find(|handler| handler.can_handle_url(url, &self.context))
+            .copied()
+    }
+
+    /// Returns everything <handler> knows about the video at <url>
+    /// (including all available formats) without downloading it.
+    pub fn extract_with(
+        &self,
+        handler: &dyn SiteDefinition,
+        url: &str,
+    ) -> Result<Option<VideoMetadata>> {
+        handler.extract(url, &self.context)
+    }
+
+    /// Finds a site definition for <url> and downloads the video.
+    pub fn download(&self, url: &str) -> Result<DownloadOutcome> {
+        match self.handler_for(url) {
+            Some(handler) => self.download_with(handler, url),
+            None => Ok(DownloadOutcome::UnsupportedUrl),
+        }
+    }
+
+    // returns the post-processing steps for <targetfile>, downloaded
+    // from <format> (and <merge_with>).
+    fn plan(
+        &self,
+        targetfile: &TargetFile,
+        format: &VideoFormat,
+        merge_with: Option<&VideoFormat>,
+    ) -> Vec<PostProcessingStep> {
+        // The codecs of the downloaded file:
+        let acodec = merge_with.unwrap_or(format).acodec.as_deref();
+        PostProcessingStep::plan(
+            targetfile,
+            format.vcodec.as_deref(),
+            acodec,
+            self.only_audio,
+            &self.audio_format,
+            self.audio_quality,
+        )
+    }
+
+    // Downloads the thumbnail of the video next to <filename>, as
+    // "<name>.thumb.<ext>" so it cannot replace an image of the user.
+    // A video without a cover is no reason to fail.
+    fn fetch_thumbnail(&self, metadata: &VideoMetadata, filename: &Path) -> Option<PathBuf> {
+        let url = metadata.thumbnail.as_deref()?;
+        let fetch = || -> Result<PathBuf> {
+            let response = self.context.http.get(url)?.call()?;
+            let ext = match response.content_type() {
+                "image/png" => "png",
+                "image/webp" => "webp",
+                _ => "jpg",
+            };
+
+            let path = filename.with_extension(format!("thumb.{}", ext));
+            let mut image = vec![];
+            response.into_reader().read_to_end(&mut image)?;
+            std::fs::write(&path, image)?;
+            Ok(path)
+        };
+
+        match fetch() {
+            Ok(path) => Some(path),
+            Err(e) => {
+                if self.verbose {
+                    println!("Could not fetch the thumbnail, skipping it: {:#}", e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Downloads the video at <url> using <handler>.
+    pub fn download_with(
+        &self,
+        handler: &dyn SiteDefinition,
+        url: &str,
+    ) -> Result<DownloadOutcome> {
+        if handler.web_driver_required() && self.context.webdriver_port == 0 {
+            // This handler would need a web driver, but none is supplied to yaydl.
+            return Ok(DownloadOutcome::WebDriverRequired(handler.display_name()));
+        }
+
+        let ready = match process_video(handler, self, url)? {
+            VideoProcessingResult::VideoNotFound => return Ok(DownloadOutcome::VideoNotFound),
+            VideoProcessingResult::TitleNotFound => return Ok(DownloadOutcome::TitleNotFound),
+            VideoProcessingResult::FormatNotFound => return Ok(DownloadOutcome::FormatNotFound),
+            VideoProcessingResult::ReadyToProcess(ready) => ready,
+        };
+
+        if self.verbose {
+            println!("Starting the download.");
+        }
+
+        let metadata = ready.metadata.clone();
+        let format = ready.format.clone();
+        let merge_with = ready.merge_with.clone();
+        let mut targetfile = TargetFile::new(*ready, &self.output_template, &self.sanitizer)?
+            .in_dir(self.output_dir.as_deref());
+
+        // The template can ask for directories which are not there yet:
+        if let Some(dir) = Path::new(&targetfile.target_filename).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Do not touch files which are there already, unless asked to:
+        let final_path = |targetfile: &TargetFile| {
+            let steps = self.plan(targetfile, &format, merge_with.as_ref());
+            Path::new(&targetfile.target_filename)
+                .with_extension(steps.last().map_or(&targetfile.target_ext[..], |s| s.ext()))
+        };
+        let parts = |targetfile: &TargetFile| match &merge_with {
+            Some(merge_with) => vec![targetfile.part(&format), targetfile.part(merge_with)],
+            None => vec![],
+        };
+
+        match self.overwrites {
+            Overwrites::Never if final_path(&targetfile).exists() => {
+                return Ok(DownloadOutcome::AlreadyDownloaded(final_path(&targetfile)));
+            }
+            Overwrites::Never => {}
+            Overwrites::Always => {
+                for file in parts(&targetfile).iter().chain([&targetfile]) {
+                    crate::download::remove_partial(&file.target_filename)?;
+                }
+                if final_path(&targetfile).exists() {
+                    std::fs::remove_file(final_path(&targetfile))?;
+                }
+            }
+            Overwrites::Rename => {
+                // Neither the downloaded nor the final file must exist:
+                let taken = |targetfile: &TargetFile| {
+                    Path::new(&targetfile.target_filename).exists()
+                        || final_path(targetfile).exists()
+                };
+
+                let original = targetfile.clone();
+                let mut n = 0;
+                while taken(&targetfile) {
+                    n += 1;
+                    targetfile = original.numbered(n);
+                }
+            }
+        }
+
+        let options = &self.download_options;
+        let ffmpeg = options.ffmpeg.with_duration(metadata.duration);
+
+        match &merge_with {
+            None => {
+                targetfile.fetch(&self.context.agent(&format.url)?, &format.url, options)?;
+            }
+            Some(merge_with) => {
+                // Download both streams at the same time, then put them together:
+                let mut parts = [
+                    (targetfile.part(&format), &format.url),
+                    (targetfile.part(merge_with), &merge_with.url),
+                ];
+
+                thread::scope(|scope| -> Result<()> {
+                    let downloads: Vec<_> = parts
+                        .iter_mut()
+                        .map(|(part, url)| {
+                            scope.spawn(move || part.fetch(&self.context.agent(url)?, url, options))
+                        })
+                        .collect();
+
+                    for download in downloads {
+                        download
+                            .join()
+                            .map_err(|_| anyhow!("A download thread panicked."))??;
+                    }
+                    Ok(())
+                })?;
+
+                if self.verbose {
+                    println!("Merging the video and the audio.");
+                }
+
+                let [(video, _), (audio, _)] = &parts;
+                ffmpeg
+                    .merge(
+                        Path::new(&video.target_filename),
+                        Path::new(&audio.target_filename),
+                        Path::new(&targetfile.target_filename),
+                    )
+                    .with_context(|| {
+                        format!(
+                            "Could not merge {} and {}, both were kept",
+                            video.target_filename, audio.target_filename
+                        )
+                    })?;
+
+                if !self.keep_temp_file {
+                    std::fs::remove_file(&video.target_filename)?;
+                    std::fs::remove_file(&audio.target_filename)?;
+                }
+            }
+        }
+
+        let mut steps = self.plan(&targetfile, &format, merge_with.as_ref());
+
+        // The tags and the cover art go into the final file:
+        let mut thumbnail = None;
+        if self.embed_metadata || self.embed_thumbnail {
+            let mut embedding = match self.embed_metadata {
+                true => Embedding::from_metadata(&metadata),
+                false => Embedding::default(),
+            };
+
+            if self.embed_thumbnail {
+                thumbnail = self.fetch_thumbnail(&metadata, Path::new(&targetfile.target_filename));
+                embedding.thumbnail = thumbnail.clone();
+            }
+
+            // Nothing to do if only the thumbnail was asked for and it could not be fetched:
+            if !embedding.is_empty() {
+                steps.push(PostProcessingStep::Embed {
+                    ext: steps
+                        .last()
+                        .map_or(targetfile.target_ext.as_str(), |step| step.ext())
+                        .to_string(),
+                    embedding,
+                });
+            }
+        }
+
+        let mut path = PathBuf::from(&targetfile.target_filename);
+        for step in &steps {
+            if self.verbose {
+                println!("Post-processing: {}.", step);
+            }
+
+            let mut paths_for = InputOutputPaths::from(path);
+            paths_for.run(step, &ffmpeg).with_context(|| {
+                format!(
+                    "Post-processing failed, the download was kept as {}",
+                    paths_for.input.display()
+                )
+            })?;
+
+            // Some steps replace their input file:
+            if !self.keep_temp_file && paths_for.input != paths_for.output {
+                std::fs::remove_file(&paths_for.input)?;
+            }
+
+            path = paths_for.output;
+        }
+
+        if let Some(thumbnail) = thumbnail.filter(|_| !self.keep_temp_file) {
+            std::fs::remove_file(thumbnail)?;
+        }
+
+        Ok(DownloadOutcome::Downloaded(Box::new(DownloadedVideo {
+            site: handler.display_name(),
+            metadata,
+            path,
+        })))
+    }
+}
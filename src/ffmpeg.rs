@@ -16,36 +16,537 @@
 // Yet Another Youtube Down Loader
 // - ffmpeg.rs file -
 
-use std::path::Path;
-use std::process::Command;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::definitions::{Chapter, VideoMetadata};
 
 // It makes very little sense to link ffmpeg statically with yaydl.
 // Just use the system's one (or inform the user if there isn't one).
 
-pub fn to_audio(inputfile: &Path, outputfile: &Path) {
-    Command::new("ffmpeg")
-        .arg("-i")
-        .arg(inputfile)
-        .arg("-vn") // Skip the video streams.
-        .arg("-loglevel")
-        .arg("quiet") // Shut the fuck up.
-        .arg(outputfile)
-        .output()
-        .expect("Please install ffmpeg to convert the file into audio.");
-}
-
-#[allow(unused)]
-pub fn ts_to_mp4(inputfile: &Path, outputfile: &Path) {
-    Command::new("ffmpeg")
-        .arg("-i")
-        .arg(inputfile)
-        .arg("-acodec")
-        .arg("copy")
-        .arg("-vcodec")
-        .arg("copy")
-        .arg("-loglevel")
-        .arg("quiet") // Shut the fuck up.
-        .arg(outputfile)
-        .output()
-        .expect("Please install ffmpeg to convert the file into MP4.");
+/// The quality of converted audio: a VBR level from 0 (best) to 10 (worst)
+/// or a bitrate in kbit/s (e.g. "128K").
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioQuality {
+    Vbr(f32),
+    Bitrate(u32),
+}
+
+impl FromStr for AudioQuality {
+    type Err = String;
+
+    fn from_str(quality: &str) -> Result<Self, Self::Err> {
+        let quality = quality.trim();
+        let invalid = || format!("Invalid audio quality: {}", quality);
+
+        if let Some(bitrate) = quality.strip_suffix(['k', 'K']) {
+            return bitrate
+                .parse()
+                .map(AudioQuality::Bitrate)
+                .map_err(|_| invalid());
+        }
+
+        match quality.parse::<f32>().map_err(|_| invalid())? {
+            level if (0.0..=10.0).contains(&level) => Ok(AudioQuality::Vbr(level)),
+            bitrate if bitrate > 10.0 && bitrate.fract() == 0.0 => {
+                Ok(AudioQuality::Bitrate(bitrate as u32))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// The audio formats yaydl knows: the name (for --audio-format),
+// the file extension, the ffmpeg encoder and the codecs which can
+// be copied into the file without re-encoding them.
+struct AudioCodec {
+    names: &'static [&'static str],
+    ext: &'static str,
+    encoder: &'static str,
+    copyable: &'static [&'static str],
+}
+
+const AUDIO_CODECS: [AudioCodec; 6] = [
+    AudioCodec {
+        names: &["mp3"],
+        ext: "mp3",
+        encoder: "libmp3lame",
+        copyable: &["mp3", "mp4a.40.34", "mp4a.6b"],
+    },
+    AudioCodec {
+        names: &["aac", "m4a"],
+        ext: "m4a",
+        encoder: "aac",
+        copyable: &["mp4a.40", "aac"],
+    },
+    AudioCodec {
+        names: &["opus"],
+        ext: "opus",
+        encoder: "libopus",
+        copyable: &["opus"],
+    },
+    AudioCodec {
+        names: &["vorbis", "ogg"],
+        ext: "ogg",
+        encoder: "libvorbis",
+        copyable: &["vorbis"],
+    },
+    AudioCodec {
+        names: &["flac"],
+        ext: "flac",
+        encoder: "flac",
+        copyable: &["flac"],
+    },
+    AudioCodec {
+        names: &["wav"],
+        ext: "wav",
+        encoder: "pcm_s16le",
+        copyable: &[],
+    },
+];
+
+fn audio_codec(format: &str) -> Option<&'static AudioCodec> {
+    let format = format.to_lowercase();
+    AUDIO_CODECS
+        .iter()
+        .find(|codec| codec.names.contains(&format.as_str()))
+}
+
+// returns the file extension for the audio format <format> (e.g. "m4a" for "aac").
+// Unknown formats are file extensions already.
+pub fn audio_ext(format: &str) -> String {
+    audio_codec(format).map_or(format.to_string(), |codec| codec.ext.to_string())
+}
+
+// returns true if an audio stream with the codec <acodec> (e.g. "mp4a.40.2")
+// fits into the audio format <format> as it is.
+pub fn can_copy_audio(acodec: &str, format: &str) -> bool {
+    let acodec = acodec.to_lowercase();
+    audio_codec(format).is_some_and(|codec| {
+        codec
+            .copyable
+            .iter()
+            .any(|copyable| acodec.starts_with(copyable))
+    })
+}
+
+// returns the ffmpeg arguments for encoding with <encoder> in <quality>.
+fn quality_args(encoder: &str, quality: Option<AudioQuality>) -> Vec<String> {
+    match quality {
+        None => vec![],
+        Some(AudioQuality::Bitrate(bitrate)) => vec!["-b:a".to_string(), format!("{}k", bitrate)],
+        Some(AudioQuality::Vbr(level)) => {
+            // The VBR scale of the encoder, from the best to the worst value.
+            // Other encoders (e.g. opus) know their best default.
+            let (best, worst) = match encoder {
+                "libmp3lame" => (0.0, 9.0),
+                "libvorbis" => (10.0, 0.0),
+                "aac" => (4.0, 0.1),
+                _ => return vec![],
+            };
+            let value = best + (worst - best) * level / 10.0;
+            vec!["-q:a".to_string(), format!("{:.1}", value)]
+        }
+    }
+}
+
+// What to write into a file besides its streams:
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Embedding {
+    // The container tags as (key, value), e.g. ("title", "...").
+    pub tags: Vec<(String, String)>,
+    pub chapters: Vec<Chapter>,
+    // The duration in seconds (where the last chapter ends).
+    pub duration: Option<u64>,
+    // The cover art image file.
+    pub thumbnail: Option<PathBuf>,
+}
+
+impl Embedding {
+    // true, if there is nothing to write into the file.
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.chapters.is_empty() && self.thumbnail.is_none()
+    }
+
+    // returns the tags and chapters of <metadata>. ffmpeg translates
+    // them into MP4 atoms, ID3 frames or Vorbis comments.
+    pub fn from_metadata(metadata: &VideoMetadata) -> Self {
+        let mut tags = vec![("title", Some(&metadata.title))];
+        tags.push(("artist", metadata.uploader.as_ref()));
+        tags.push(("date", metadata.upload_date.as_ref()));
+        tags.push(("description", metadata.description.as_ref()));
+        tags.push(("comment", metadata.description.as_ref()));
+
+        Self {
+            tags: tags
+                .into_iter()
+                .filter_map(|(key, value)| Some((key.to_string(), value?.clone())))
+                .filter(|(_, value)| !value.is_empty())
+                .collect(),
+            chapters: metadata.chapters.clone(),
+            duration: metadata.duration,
+            thumbnail: None,
+        }
+    }
+
+    // returns the contents of an FFMETADATA file with the tags, the chapters
+    // and, if given, the <picture> as an additional tag.
+    fn ffmetadata(&self, picture: Option<(String, String)>) -> String {
+        // '=', ';', '#', '\' and line breaks need to be escaped:
+        let escape = |value: &str| {
+            value.chars().fold(String::new(), |mut escaped, c| {
+                if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+                escaped
+            })
+        };
+
+        let mut contents = String::from(";FFMETADATA1\n");
+        for (key, value) in self.tags.iter().cloned().chain(picture) {
+            contents.push_str(&format!("{}={}\n", escape(&key), escape(&value)));
+        }
+
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            let end = match self.chapters.get(i + 1) {
+                Some(next) => next.start,
+                None => self.duration.unwrap_or(chapter.start).max(chapter.start),
+            };
+            contents.push_str(&format!(
+                "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+                chapter.start * 1000,
+                end * 1000,
+                escape(&chapter.title)
+            ));
+        }
+
+        contents
+    }
+}
+
+// returns the MIME type of the image file <path>.
+fn image_mimetype(path: &Path) -> &'static str {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+// returns the image file <path> as a FLAC picture block, base64-encoded,
+// which is how Ogg files (Vorbis, Opus) store their cover art.
+fn metadata_block_picture(path: &Path) -> Result<String> {
+    let image = fs::read(path)?;
+    let mimetype = image_mimetype(path);
+
+    let mut block = vec![];
+    block.extend(3u32.to_be_bytes()); // The front cover.
+    block.extend((mimetype.len() as u32).to_be_bytes());
+    block.extend(mimetype.as_bytes());
+    block.extend(0u32.to_be_bytes()); // No description,
+    block.extend([0u8; 16]); // no known size, depth and palette.
+    block.extend((image.len() as u32).to_be_bytes());
+    block.extend(image);
+
+    Ok(base64::engine::general_purpose::STANDARD.encode(block))
+}
+
+#[derive(Debug, Clone)]
+pub struct Ffmpeg {
+    // The ffmpeg binary, "ffmpeg" (from the PATH) by default.
+    binary: PathBuf,
+    // The duration of the input in seconds, if known (for the progress bar).
+    duration: Option<u64>,
+}
+
+impl Default for Ffmpeg {
+    fn default() -> Self {
+        Self {
+            binary: PathBuf::from("ffmpeg"),
+            duration: None,
+        }
+    }
+}
+
+impl Ffmpeg {
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            duration: None,
+        }
+    }
+
+    pub fn with_duration(&self, duration: Option<u64>) -> Self {
+        Self {
+            duration,
+            ..self.clone()
+        }
+    }
+
+    // Runs ffmpeg with <args>, writing <outputfile>. A failed (or killed)
+    // run leaves no half-written <outputfile> behind: ffmpeg writes into
+    // "<name>.part.<ext>" (it picks the container by the extension), which
+    // is only renamed when it is complete.
+    fn run(&self, args: &[&OsStr], outputfile: &Path) -> Result<()> {
+        let partfile = outputfile.with_extension(match outputfile.extension() {
+            Some(ext) => format!("part.{}", ext.to_string_lossy()),
+            None => "part".to_string(),
+        });
+
+        let mut child = Command::new(&self.binary)
+            .arg("-hide_banner")
+            .arg("-nostats")
+            .arg("-loglevel")
+            .arg("error") // Only talk if something went wrong.
+            .arg("-progress")
+            .arg("pipe:1") // Report the progress to us.
+            .arg("-y") // yaydl chooses the output files itself.
+            .args(args)
+            .arg(&partfile)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => anyhow!(
+                    "{} was not found. Please install ffmpeg or use --ffmpeg-location.",
+                    self.binary.display()
+                ),
+                _ => anyhow!("Could not run {}: {}", self.binary.display(), e),
+            })?;
+
+        // Collect the error messages while the progress is read:
+        let mut stderr = child.stderr.take().unwrap();
+        let errors = thread::spawn(move || {
+            let mut errors = String::new();
+            let _ = stderr.read_to_string(&mut errors);
+            errors
+        });
+
+        let pb = match self.duration {
+            Some(duration) => ProgressBar::new(duration * 1000),
+            None => ProgressBar::new_spinner(),
+        };
+        pb.set_style(
+            ProgressStyle::with_template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:40.green/blue}] {percent}%",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+        );
+
+        // ffmpeg writes "key=value" lines, e.g. out_time_us=1234567:
+        for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+            if let Some(microseconds) = line?
+                .strip_prefix("out_time_us=")
+                .and_then(|us| us.parse::<u64>().ok())
+            {
+                pb.set_position(microseconds / 1000);
+            }
+        }
+
+        let status = child.wait()?;
+        pb.finish_and_clear();
+        let errors = errors.join().unwrap_or_default();
+
+        if !status.success() {
+            let _ = fs::remove_file(&partfile);
+            return Err(anyhow!(
+                "ffmpeg failed ({}) to write {}: {}",
+                status,
+                outputfile.display(),
+                errors.trim()
+            ));
+        }
+
+        fs::rename(&partfile, outputfile)?;
+        Ok(())
+    }
+
+    // Converts the audio of <inputfile> into <format> (see AUDIO_CODECS).
+    pub fn to_audio(
+        &self,
+        inputfile: &Path,
+        outputfile: &Path,
+        format: &str,
+        quality: Option<AudioQuality>,
+    ) -> Result<()> {
+        let mut args: Vec<&OsStr> = vec![
+            "-i".as_ref(),
+            inputfile.as_ref(),
+            "-vn".as_ref(), // Skip the video streams.
+        ];
+
+        // Unknown formats are left to ffmpeg:
+        let codec = audio_codec(format);
+        let quality = quality_args(codec.map_or("", |codec| codec.encoder), quality);
+        if let Some(codec) = codec {
+            args.push("-acodec".as_ref());
+            args.push(codec.encoder.as_ref());
+        }
+        args.extend(quality.iter().map(OsStr::new));
+
+        self.run(&args, outputfile)
+    }
+
+    // Keeps the audio of <inputfile> without re-encoding it.
+    pub fn copy_audio(&self, inputfile: &Path, outputfile: &Path) -> Result<()> {
+        self.run(
+            &[
+                "-i".as_ref(),
+                inputfile.as_ref(),
+                "-vn".as_ref(), // Skip the video streams.
+                "-acodec".as_ref(),
+                "copy".as_ref(),
+            ],
+            outputfile,
+        )
+    }
+
+    // Copies the streams into the container of <outputfile> without re-encoding them.
+    pub fn remux(&self, inputfile: &Path, outputfile: &Path) -> Result<()> {
+        self.run(
+            &[
+                "-i".as_ref(),
+                inputfile.as_ref(),
+                "-acodec".as_ref(),
+                "copy".as_ref(),
+                "-vcodec".as_ref(),
+                "copy".as_ref(),
+            ],
+            outputfile,
+        )
+    }
+
+    // Re-encodes the video and the audio for the container of <outputfile>.
+    pub fn transcode(&self, inputfile: &Path, outputfile: &Path) -> Result<()> {
+        self.run(
+            &[
+                "-i".as_ref(),
+                inputfile.as_ref(),
+                "-vcodec".as_ref(),
+                "libx264".as_ref(),
+                "-acodec".as_ref(),
+                "aac".as_ref(),
+            ],
+            outputfile,
+        )
+    }
+
+    // Writes <inputfile> with the tags, the chapters and the cover art of
+    // <embedding> as <outputfile>. Containers without a way to store
+    // cover art (e.g. WebM) only get the tags and the chapters.
+    pub fn embed(&self, inputfile: &Path, outputfile: &Path, embedding: &Embedding) -> Result<()> {
+        let ext = outputfile
+            .extension()
+            .and_then(OsStr::to_str)
+            .unwrap_or("")
+            .to_lowercase();
+        let thumbnail = embedding.thumbnail.as_deref();
+
+        // Ogg files want their cover art as a tag:
+        let picture = match (thumbnail, ext.as_str()) {
+            (Some(thumbnail), "ogg" | "opus" | "oga") => Some((
+                "METADATA_BLOCK_PICTURE".to_string(),
+                metadata_block_picture(thumbnail)?,
+            )),
+            _ => None,
+        };
+
+        let metafile = outputfile.with_extension("ffmeta");
+        fs::write(&metafile, embedding.ffmetadata(picture))?;
+
+        let mut inputs: Vec<&OsStr> = vec![
+            "-i".as_ref(),
+            inputfile.as_ref(),
+            "-i".as_ref(),
+            metafile.as_ref(),
+        ];
+        let mut outputs: Vec<OsString> = [
+            "-map",
+            "0",
+            "-map_metadata",
+            "1", // The tags
+            "-map_chapters",
+            "1", // and the chapters from the FFMETADATA file.
+            "-c",
+            "copy",
+        ]
+        .map(OsString::from)
+        .to_vec();
+
+        match (thumbnail, ext.as_str()) {
+            (Some(thumbnail), "mp4" | "m4v" | "mov" | "m4a" | "mp3" | "flac") => {
+                // The cover is an additional video stream. Audio files
+                // do not have any other one:
+                let cover = match ext.as_str() {
+                    "m4a" | "mp3" | "flac" => "v:0",
+                    _ => "v:1",
+                };
+                inputs.extend::<[&OsStr; 2]>(["-i".as_ref(), thumbnail.as_ref()]);
+                outputs.extend(
+                    [
+                        "-map".to_string(),
+                        "2".to_string(),
+                        format!("-c:{}", cover),
+                        "mjpeg".to_string(),
+                        format!("-disposition:{}", cover),
+                        "attached_pic".to_string(),
+                    ]
+                    .map(OsString::from),
+                );
+            }
+            (Some(thumbnail), "mkv" | "mka") => {
+                // Matroska has attachments for that:
+                outputs.extend([
+                    OsString::from("-attach"),
+                    thumbnail.into(),
+                    "-metadata:s:t".into(),
+                    format!("mimetype={}", image_mimetype(thumbnail)).into(),
+                ]);
+            }
+            _ => {}
+        }
+
+        if ext == "mp3" {
+            // ID3v2.4 is still not understood everywhere.
+            outputs.extend(["-id3v2_version".into(), "3".into()]);
+        }
+
+        let args: Vec<&OsStr> = inputs
+            .into_iter()
+            .chain(outputs.iter().map(OsString::as_os_str))
+            .collect();
+        let result = self.run(&args, outputfile);
+        let _ = fs::remove_file(&metafile);
+        result
+    }
+
+    pub fn merge(&self, videofile: &Path, audiofile: &Path, outputfile: &Path) -> Result<()> {
+        self.run(
+            &[
+                "-i".as_ref(),
+                videofile.as_ref(),
+                "-i".as_ref(),
+                audiofile.as_ref(),
+                "-map".as_ref(),
+                "0:v".as_ref(), // The video from the first file,
+                "-map".as_ref(),
+                "1:a".as_ref(), // the audio from the second one.
+                "-c".as_ref(),
+                "copy".as_ref(),
+            ],
+            outputfile,
+        )
+    }
 }
@@ -17,8 +17,7 @@
 // https://spankbang.com/5-char-id/video/description+seprated+by+plus+char
 //
 // example:  https://spankbang.com/12345/video/description+for+this+video
-// base filename: description_for_this_video lenght maximum is 142
-// filename: description_for_this_video-12345.mp4
+// title: description for this video
 //
 // test url: https://spankbang.com/70841/video/nikki+fritz
 //
@@ -26,99 +25,91 @@
 // Yet Another Youtube Down Loader
 // - Spankbang handler -
 
-use crate::definitions::SiteDefinition;
+use crate::definitions::{HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
 
 use anyhow::Result;
 use regex::Regex;
 use scraper::{Html, Selector};
 use url::Url;
 
-use crate::VIDEO;
-
-const MAX_FILENAME_LENGTH: usize = 142; // filename is based on url path description string
-
-fn get_video_info(video: &mut VIDEO, url: &str) -> Result<bool> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // It will contain the whole body for now.
-        let local_url = url.to_owned();
-        video.info.push_str(
-            ureq::get(&local_url)
-                .call()
-                .expect("Could not go to the url")
-                .into_string()
-                .expect("Could not read the site source")
-                .as_str(),
-        );
-    }
-
-    Ok(true)
+fn get_video_info(url: &str, ctx: &HandlerContext) -> Result<Html> {
+    // We need to fetch the video information first.
+    // It will contain the whole body for now.
+    let body = ctx.fetch(url)?;
+    Ok(Html::parse_document(&body))
 }
 
 // Implement the site definition:
 struct SpankbangHandler;
 impl SiteDefinition for SpankbangHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, _ctx: &HandlerContext) -> bool {
         Regex::new(r"spankbang.com/.+").unwrap().is_match(url)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
-        // Generic has playlists.
-        Ok(false)
-    }
-
-    fn find_video_title<'a>(
-        &'a self,
-        _video: &mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<String> {
-        // generates a valid base filename from url path for linux and windows
-        // video title is less reliable to generate base filename for this particular site
-        Ok(url_filename(url.to_string()))
-    }
-
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        let _not_used = get_video_info(video, url);
-        let video_info_html = Html::parse_document(&video.info);
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
+        let video_info_html = get_video_info(url, ctx)?;
 
+        // Spankbang offers one <source> per quality. The height is either
+        // an attribute or a part of the file name (e.g. "...-720p.mp4"):
+        let height_re = Regex::new(r"(\d{3,4})p").unwrap();
         let url_selector = Selector::parse(r#"source[type="video/mp4"]"#).unwrap();
-        let url_elem = video_info_html.select(&url_selector).next().unwrap();
-        let url_contents = url_elem.value().attr("src").unwrap();
-
-        Ok(url_contents.to_string())
-    }
+        let formats: Vec<VideoFormat> = video_info_html
+            .select(&url_selector)
+            .filter_map(|url_elem| {
+                let url_contents = url_elem.value().attr("src")?;
+                let height = ["size", "res", "label"]
+                    .iter()
+                    .filter_map(|attr| url_elem.value().attr(attr))
+                    .find_map(|value| value.trim_end_matches('p').parse::<u64>().ok())
+                    .or_else(|| {
+                        height_re
+                            .captures(url_contents)
+                            .and_then(|c| c[1].parse().ok())
+                    });
+
+                Some(VideoFormat {
+                    id: height.map_or("http".to_string(), |h| format!("http-{}p", h)),
+                    url: url_contents.to_string(),
+                    ext: "mp4".to_string(),
+                    height,
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        if formats.is_empty() {
+            return Ok(None);
+        }
 
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<bool> {
-        let _not_used = get_video_info(video, url);
-        Ok(!video.info.is_empty())
+        let thumbnail_selector = Selector::parse(r#"meta[property="og:image"]"#).unwrap();
+        let thumbnail = video_info_html
+            .select(&thumbnail_selector)
+            .next()
+            .and_then(|elem| elem.value().attr("content"))
+            .map(str::to_string);
+
+        // https://spankbang.com/12345/video/description+for+this+video
+        let id = Url::parse(url)?
+            .path_segments()
+            .and_then(|mut segments| segments.next())
+            .unwrap_or("")
+            .to_string();
+
+        Ok(Some(VideoMetadata {
+            id,
+            // The video title is less reliable than the url path for this particular site:
+            title: url_title(url),
+            thumbnail,
+            // Spankbang has no playlists.
+            formats,
+            ..Default::default()
+        }))
     }
 
     fn display_name(&self) -> String {
         "Spankbang".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        _video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        Ok("mp4".to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         false
     }
@@ -129,68 +120,20 @@ inventory::submit! {
     &SpankbangHandler as &dyn SiteDefinition
 }
 
-// covert url path to base filename
+// converts the url path to the video title
 // path pattern is specific to web sites
-fn url_filename(url: String) -> String {
+fn url_title(url: &str) -> String {
     // https://spankbang.com/12345/video/description+for+this+video
-    // extract url path
-    let path = match Url::parse(&url) {
-        Ok(urlx) => urlx.path().to_string(),
-        Err(e) => {
-            println!("Error: Could not parse '{}'. {}.", url, e);
-            "url_filename_parse_error".to_string()
-        }
+    let Ok(url) = Url::parse(url) else {
+        return String::new();
     };
 
     // path = /12345/video/description+for+this+video
-    let vec: Vec<&str> = path.as_str().split("/").collect();
-    let id_5char = vec[1]; // = 12345
-    let description = vec[3]; // use for base filename, = description+for+this+video
-
-    let mut base_filename = format!("{}-{}", description, id_5char); // concat then converts &str to String
-
-    if description.len() > MAX_FILENAME_LENGTH {
-        let (shorten, _) = description.split_at(MAX_FILENAME_LENGTH); // shorten description
-        base_filename = format!("{}...-{}", shorten, id_5char); // concat using shorten description
-    }
-
-    windows_filename(linux_filename(base_filename))
-}
-
-// replace invalid linux chars with _ underscore
-fn linux_filename(in_filename: String) -> String {
-    // let out_filename = format!(
-    //     "{}",
-    //     in_filename.trim().replace(
-    //         &['|', '\'', '\"', ':', '\'', '\\', '/'][..], // '"', also works for quote char
-    //         r#"_"#
-    //     )
-    // );
-
-    in_filename
-        .trim()
-        .replace(
-            &['|', '\'', '\"', ':', '\'', '\\', '/'][..], // '"', also works for quote char
-            r#"_"#,
-        )
-        .to_string()
-}
+    let description = url.path_segments().and_then(|mut segments| segments.nth(2));
 
-// replace invalid windows chars with _ underscore
-fn windows_filename(in_filename: String) -> String {
-    // let out_filename = format!(
-    //     "{}",
-    //     in_filename
-    //         .trim()
-    //         // also replace newline char
-    //         // replacing plus '+' char is specific to spankbang
-    //         .replace(&['<', '>', ':', '?', '*', '\n', '+'][..], r#"_"#) // replace with underscore char
-    // );
-
-    in_filename
-        .trim()
-        // also replace newline char
-        // replacing plus '+' char is specific to spankbang
-        .replace(&['<', '>', ':', '?', '*', '\n', '+'][..], r#"_"#)
-        .to_string()
+    // The file name is made from the title later:
+    description.map_or(String::new(), |description| {
+        urlencoding::decode(&description.replace('+', " "))
+            .map_or(description.to_string(), |title| title.into_owned())
+    })
 }
@@ -16,100 +16,70 @@
 // Yet Another Youtube Down Loader
 // - Vidoza handler -
 
-use crate::definitions::SiteDefinition;
+use crate::definitions::{HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
 
 use anyhow::Result;
 use regex::Regex;
 use scraper::{Html, Selector};
 
-use crate::VIDEO;
-
-fn get_video_info(video: &mut VIDEO, url: &str) -> Result<Html> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // It will contain the whole body for now.
-        let req = ureq::get(url).call()?;
-        let body = req.into_string()?;
-
-        video.info = body;
-    }
+fn get_video_info(url: &str, ctx: &HandlerContext) -> Result<Html> {
+    // We need to fetch the video information first.
+    // It will contain the whole body for now.
+    let body = ctx.fetch(url)?;
 
     // Return it:
-    let d = Html::parse_document(&video.info);
+    let d = Html::parse_document(&body);
     Ok(d)
 }
 
 // Implement the site definition:
 struct VidozaHandler;
 impl SiteDefinition for VidozaHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, _ctx: &HandlerContext) -> bool {
         Regex::new(r"vid(oza|ezz).net/.+").unwrap().is_match(url)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
-        // Vidoza does not seem to have playlists?
-        Ok(false)
-    }
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
+        let video_info = get_video_info(url, ctx)?;
 
-    fn find_video_title<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<String> {
-        let video_info = get_video_info(video, url)?;
+        let url_selector = Selector::parse("source").unwrap();
+        let Some(url_contents) = video_info
+            .select(&url_selector)
+            .next()
+            .and_then(|url_elem| url_elem.value().attr("src"))
+        else {
+            return Ok(None);
+        };
 
         // Currently, there only is one <H1> on Vidoza. Good for us.
         let h1_selector = Selector::parse("h1").unwrap();
-        let text = video_info.select(&h1_selector).next();
-        let result = match text {
+        let title = match video_info.select(&h1_selector).next() {
             Some(value) => value.text().collect::<String>(),
             None => "Vidoza".to_string(),
         };
 
-        Ok(result)
-    }
-
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        let video_info = get_video_info(video, url)?;
-
-        let url_selector = Selector::parse("source").unwrap();
-        let url_elem = video_info.select(&url_selector).next().unwrap();
-        let url_contents = url_elem.value().attr("src").unwrap();
-
-        Ok(url_contents.to_string())
-    }
-
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<bool> {
-        let _video_info = get_video_info(video, url);
-        Ok(!video.info.is_empty())
+        // The video ID is the last part of the URL path:
+        let id = url.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+
+        Ok(Some(VideoMetadata {
+            id: id.to_string(),
+            title,
+            // Vidoza does not seem to have playlists?
+            formats: vec![VideoFormat {
+                id: "http".to_string(),
+                url: url_contents.to_string(),
+                ext: "mp4".to_string(),
+                is_playlist: false,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }))
     }
 
     fn display_name(&self) -> String {
         "Vidoza".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        _video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        Ok("mp4".to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         false
     }
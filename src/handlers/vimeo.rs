@@ -16,130 +16,124 @@
 // Yet Another Youtube Down Loader
 // - Vimeo handler -
 
-use crate::definitions::SiteDefinition;
+use crate::definitions::{HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
 
 use anyhow::Result;
 use regex::Regex;
 use serde_json::Value;
 
-use crate::VIDEO;
-
-fn get_video_info(video: &mut VIDEO, url: &str) -> Result<Value> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // Those are hidden behing a config file defined in the page source code.
-        // Search for: window.vimeo.clip_page_config.player = {"config_url":"(.+?)"
-        let req = ureq::get(url).call()?;
-        let body = req.into_string()?;
-        let re =
-            Regex::new("window.vimeo.clip_page_config.player = .\"config_url\":\"(?P<URL>.+?)\"")
-                .unwrap();
-        let search = re.captures(&body).unwrap();
-
-        // While we're grepping the source code: Vimeo also hides
-        // the video title here.
-        let title_re =
-            Regex::new("<meta property=\"og:title\" content=\"(?P<TITLE>.+?)\"").unwrap();
-        let title_search = title_re.captures(&body).unwrap();
-        let video_title = title_search.name("TITLE").map_or("", |t| t.as_str());
-        video.title = video_title.to_string();
-
-        // If yaydl stops here, the URL is invalid.
-        // TODO: That should be more obvious to the user.
-        let video_info_url = search
-            .name("URL")
-            .map_or("", |u| u.as_str())
-            .replace("\\", "");
-
-        // The "config_url" body is a JSON structure.
-        // Grab and store it:
-        let config_req = ureq::get(&video_info_url).call()?;
-        let config_body = config_req.into_string()?;
-        video.info.push_str(config_body.as_str());
-    }
-
-    // Return it:
-    let v: Value = serde_json::from_str(&video.info)?;
-    Ok(v)
+fn get_video_info(url: &str, ctx: &HandlerContext) -> Result<Option<(String, Value)>> {
+    // We need to fetch the video information first.
+    // Those are hidden behing a config file defined in the page source code.
+    // Search for: window.vimeo.clip_page_config.player = {"config_url":"(.+?)"
+    let body = ctx.fetch(url)?;
+    let re = Regex::new("window.vimeo.clip_page_config.player = .\"config_url\":\"(?P<URL>.+?)\"")
+        .unwrap();
+
+    // If yaydl stops here, the URL is invalid.
+    let Some(search) = re.captures(&body) else {
+        return Ok(None);
+    };
+
+    // While we're grepping the source code: Vimeo also hides
+    // the video title here.
+    let title_re = Regex::new("<meta property=\"og:title\" content=\"(?P<TITLE>.+?)\"").unwrap();
+    let video_title = title_re
+        .captures(&body)
+        .and_then(|c| c.name("TITLE"))
+        .map_or("", |t| t.as_str())
+        .to_string();
+
+    let video_info_url = search
+        .name("URL")
+        .map_or("", |u| u.as_str())
+        .replace("\\", "");
+
+    // The "config_url" body is a JSON structure.
+    // Grab and return it:
+    let config_body = ctx.fetch(&video_info_url)?;
+    let v: Value = serde_json::from_str(&config_body)?;
+    Ok(Some((video_title, v)))
 }
 
 // Implement the site definition:
 struct VimeoHandler;
 impl SiteDefinition for VimeoHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, _ctx: &HandlerContext) -> bool {
         Regex::new(r"(?:www\.)?vimeo.com/.+").unwrap().is_match(url)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
         // Vimeo seems to have no playlists?
-        Ok(false)
-    }
-
-    fn find_video_title<'a>(
-        &'a self,
-        video: &mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<String> {
-        let ret = &video.title;
-        Ok(ret.to_string())
-    }
+        let Some((title, video_info)) = get_video_info(url, ctx)? else {
+            return Ok(None);
+        };
 
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        let id_regex = Regex::new(r"(?:vimeo.com/)(.*$)").unwrap();
-        let id = id_regex.captures(url).unwrap().get(1).unwrap().as_str();
-        let video_info = get_video_info(video, id)?;
+        let files = &video_info["request"]["files"];
         let video_info_streams_progressive =
-            match video_info["request"]["files"]["progressive"].as_array() {
-                None => return Ok("".to_string()),
-                Some(streams) => streams,
-            };
+            files["progressive"].as_array().cloned().unwrap_or_default();
 
         // Vimeo makes it easy for us, as the size grows with the quality.
-        // Thus, we can just take the largest width here.
-        let mut url = "";
-        let mut width = 0u64;
-        for stream in video_info_streams_progressive.iter() {
-            let this_width = stream["width"].as_u64().unwrap_or(0);
-            if this_width > width {
-                width = this_width;
-                url = stream["url"].as_str().unwrap();
-            }
+        // Thus, we can just sort by the width here.
+        let mut streams: Vec<&Value> = video_info_streams_progressive.iter().collect();
+        streams.sort_by_key(|stream| stream["width"].as_u64().unwrap_or(0));
+
+        let mut formats: Vec<VideoFormat> = streams
+            .iter()
+            .filter_map(|stream| {
+                Some(VideoFormat {
+                    id: format!("http-{}", stream["quality"].as_str().unwrap_or("unknown")),
+                    url: stream["url"].as_str()?.to_string(),
+                    ext: "mp4".to_string(),
+                    width: stream["width"].as_u64(),
+                    height: stream["height"].as_u64(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        // Newer videos only have their best qualities as DASH. Vimeo links
+        // to its own JSON format, but has an MPD next to it:
+        let dash = &files["dash"];
+        if let Some(dash_url) = dash["default_cdn"]
+            .as_str()
+            .and_then(|cdn| dash["cdns"][cdn]["url"].as_str())
+        {
+            let manifest_url = dash_url
+                .replace("/master.json", "/master.mpd")
+                .replace("/playlist.json", "/playlist.mpd");
+            formats.extend(crate::dash::manifest_formats(ctx, &manifest_url).unwrap_or_default());
         }
 
-        Ok(url.to_string())
-    }
+        if formats.is_empty() {
+            return Ok(None);
+        }
 
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<bool> {
-        let _video_info = get_video_info(video, url);
-        Ok(!video.info.is_empty())
+        let video = &video_info["video"];
+        let id = match &video["id"] {
+            Value::Number(id) => id.to_string(),
+            _ => Regex::new(r"(?:vimeo.com/)(.*$)")
+                .unwrap()
+                .captures(url)
+                .map_or("", |c| c.get(1).map_or("", |m| m.as_str()))
+                .to_string(),
+        };
+
+        Ok(Some(VideoMetadata {
+            id,
+            title,
+            uploader: video["owner"]["name"].as_str().map(str::to_string),
+            duration: video["duration"].as_u64(),
+            thumbnail: video["thumbs"]["base"].as_str().map(str::to_string),
+            formats,
+            ..Default::default()
+        }))
     }
 
     fn display_name(&self) -> String {
         "Vimeo".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        _video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        Ok("mp4".to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         false
     }
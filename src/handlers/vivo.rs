@@ -16,7 +16,7 @@
 // Yet Another Youtube Down Loader
 // - VIVO handler -
 
-use crate::definitions::SiteDefinition;
+use crate::definitions::{HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
 
 use anyhow::Result;
 use cienli::ciphers::rot::{Rot, RotType};
@@ -24,96 +24,65 @@ use regex::Regex;
 use scraper::{Html, Selector};
 use urlencoding::decode;
 
-use crate::VIDEO;
-
-fn get_video_info(video: &mut VIDEO, url: &str) -> Result<Html> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // It will contain the whole body for now.
-        let req = ureq::get(url).call()?;
-        let body = req.into_string()?;
-        video.info.push_str(body.as_str());
-    }
-
-    let d = Html::parse_document(&video.info);
-    Ok(d)
+fn get_video_info(url: &str, ctx: &HandlerContext) -> Result<String> {
+    // We need to fetch the video information first.
+    // It will contain the whole body for now.
+    ctx.fetch(url)
 }
 
 // Implement the site definition:
 struct VivoHandler;
 impl SiteDefinition for VivoHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, _ctx: &HandlerContext) -> bool {
         Regex::new(r"vivo.sx/.+").unwrap().is_match(url)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
-        // Vivo has no playlists.
-        Ok(false)
-    }
-
-    fn find_video_title<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<String> {
-        let video_info = get_video_info(video, url)?;
-
-        let title_selector = Selector::parse("div.stream-content").unwrap();
-        let title_elem = video_info.select(&title_selector).next().unwrap();
-        let title_contents = title_elem.value().attr("data-name").unwrap();
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
+        let body = get_video_info(url, ctx)?;
+        let video_info = Html::parse_document(&body);
 
-        Ok(title_contents.to_string())
-    }
-
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
         // VIVO displays the stream URL only after executing JavaScript.
         // It is buried inside the source code and ROT47-encrypted. Bah... :-)
         let src_re = Regex::new("source: '(?P<SOURCE>.+?)',").unwrap();
-        let src_search = src_re.captures(&video.info).unwrap();
-        let video_src = src_search.name("SOURCE").map_or("", |t| t.as_str());
+        let Some(video_src) = src_re.captures(&body).and_then(|c| c.name("SOURCE")) else {
+            return Ok(None);
+        };
 
         // URL decoding:
-        let url_decoded = match decode(video_src) {
-            Ok(u) => u,
-            _ => unreachable!(),
-        };
+        let url_decoded = decode(video_src.as_str())?;
 
         // un-ROT47:
         let unrotated = Rot::new(&url_decoded, RotType::Rot47);
-        Ok(unrotated.decipher().to_string())
-    }
 
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<bool> {
-        let _video_info = get_video_info(video, url);
-        Ok(!video.info.is_empty())
+        let title_selector = Selector::parse("div.stream-content").unwrap();
+        let title = video_info
+            .select(&title_selector)
+            .next()
+            .and_then(|title_elem| title_elem.value().attr("data-name"))
+            .unwrap_or("");
+
+        // The video ID is the last part of the URL path:
+        let id = url.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+
+        Ok(Some(VideoMetadata {
+            id: id.to_string(),
+            title: title.to_string(),
+            // Vivo has no playlists.
+            formats: vec![VideoFormat {
+                id: "http".to_string(),
+                url: unrotated.decipher().to_string(),
+                ext: "mp4".to_string(),
+                is_playlist: false,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }))
     }
 
     fn display_name(&self) -> String {
         "VIVO".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        _video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        Ok("mp4".to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         false
     }
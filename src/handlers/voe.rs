@@ -16,122 +16,90 @@
 // Yet Another Youtube Down Loader
 // - VOE handler -
 
-use crate::definitions::SiteDefinition;
+use crate::definitions::{HandlerContext, SiteDefinition, VideoMetadata};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use regex::Regex;
 use scraper::{Html, Selector};
 
-use crate::VIDEO;
-
-fn resolve_js_redirect(url: &str) -> String {
+fn resolve_js_redirect(url: &str, ctx: &HandlerContext) -> Result<String> {
     // VOE tends to redirect. Find the actual target URL:
-    let req = ureq::get(url).call().unwrap();
-    let body = req.into_string().unwrap();
+    let body = ctx.fetch(url)?;
 
     let re_redirect = Regex::new(r"window.location.href = '(?P<URL>.*?)'").unwrap();
     if !re_redirect.is_match(&body) {
         // No redirect
-        String::from(url)
+        Ok(String::from(url))
     } else {
         // A redirect...
         let captures = re_redirect.captures(body.as_str()).unwrap();
         let returnval = String::from(captures.name("URL").map_or("", |u| u.as_str()));
-        returnval
+        Ok(returnval)
     }
 }
 
-fn get_video_info(video: &mut VIDEO, url: &str) -> Result<Html> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // It will contain the whole body for now.
-        let req = ureq::get(&resolve_js_redirect(url)).call()?;
-        let body = req.into_string()?;
-
-        video.info = body;
-    }
-
-    // Return it:
-    let d = Html::parse_document(&video.info);
-    Ok(d)
+fn get_video_info(url: &str, ctx: &HandlerContext) -> Result<String> {
+    // We need to fetch the video information first.
+    // It will contain the whole body for now.
+    ctx.fetch(&resolve_js_redirect(url, ctx)?)
 }
 
 // Implement the site definition:
 struct VoeHandler;
 impl SiteDefinition for VoeHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> bool {
         // We need to catch both VOE.sx and whatever redirectors it uses.
-        // As main.rs hasn't built the VIDEO struct here yet, we'll parse
+        // As we haven't extracted anything here yet, we'll parse
         // the resulting website a first time...
-        let req = ureq::get(&resolve_js_redirect(url)).call().unwrap();
-        let body = req.into_string().unwrap();
+        // (A site we cannot reach is none of ours. This is asked for
+        // every URL, so do not retry.)
+        let probe = HandlerContext {
+            retries: 0,
+            ..ctx.clone()
+        };
+        let Ok(body) = get_video_info(url, &probe) else {
+            return false;
+        };
 
         // If the body contains a VOEPlayer, we're in it.
         Regex::new(r"VOEPlayer").unwrap().is_match(&body)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
-        Ok(true)
-    }
-
-    fn find_video_title<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<String> {
-        let video_info = get_video_info(video, url)?;
-
-        let h1_selector = Selector::parse("h1.mt-1").unwrap();
-        let text = video_info.select(&h1_selector).next();
-
-        let result = match text {
-            Some(txt) => txt.text().collect(),
-            None => return Err(anyhow!("Erroneous video site - maybe embed-only?")),
-        };
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
+        let body = get_video_info(url, ctx)?;
+        let video_info = Html::parse_document(&body);
 
-        Ok(result)
-    }
-
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        let _video_info = get_video_info(video, url)?;
         let url_re = Regex::new(r#"Node", "(?P<URL>[^"]+)"#).unwrap();
-        let url_search = url_re.captures(&video.info).unwrap();
-        let video_url = url_search.name("URL").map_or("", |u| u.as_str());
-
-        Ok(video_url.to_string())
-    }
+        let Some(video_url) = url_re.captures(&body).and_then(|c| c.name("URL")) else {
+            return Ok(None);
+        };
 
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<bool> {
-        let _video_info = get_video_info(video, url);
-        Ok(!video.info.is_empty())
+        // An empty title means an erroneous video site - maybe embed-only?
+        let h1_selector = Selector::parse("h1.mt-1").unwrap();
+        let title = video_info
+            .select(&h1_selector)
+            .next()
+            .map(|txt| txt.text().collect())
+            .unwrap_or_default();
+
+        // The video ID is the last part of the URL path:
+        let id = url.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+
+        // VOE usually serves a master playlist with several variants:
+        let formats = crate::download::playlist_formats(ctx, video_url.as_str())?;
+
+        Ok(Some(VideoMetadata {
+            id: id.to_string(),
+            title,
+            formats,
+            ..Default::default()
+        }))
     }
 
     fn display_name(&self) -> String {
         "Voe".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        _video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        Ok("mp4".to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         false
     }
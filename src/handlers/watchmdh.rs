@@ -16,112 +16,62 @@
 // Yet Another Youtube Down Loader
 // - WatchMDH handler -
 
-use crate::definitions::SiteDefinition;
+use crate::definitions::{HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
 
 use anyhow::Result;
-use fantoccini::ClientBuilder;
 use regex::Regex;
 use scraper::{Html, Selector};
-use tokio::runtime;
-
-use crate::VIDEO;
-
-fn get_video_info(video: &mut VIDEO, url: &str, webdriver_port: u16) -> Result<bool> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // It will contain the whole body for now.
-        let local_url = url.to_owned();
-
-        let rt = runtime::Builder::new_current_thread()
-            .enable_time()
-            .enable_io()
-            .build()
-            .unwrap();
-        rt.block_on(async move {
-            let webdriver_url = format!("http://localhost:{}", webdriver_port);
-            let c = ClientBuilder::native()
-                .connect(&webdriver_url)
-                .await
-                .expect("failed to connect to web driver");
-            c.goto(&local_url).await.expect("could not go to the URL");
-            let body = c.source().await.expect("could not read the site source");
-            video.info.push_str(body.as_str());
-            c.close_window().await.expect("could not close the window");
-        });
-    }
-
-    Ok(true)
-}
 
 // Implement the site definition:
 struct WatchMDHHandler;
 impl SiteDefinition for WatchMDHHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, _ctx: &HandlerContext) -> bool {
         Regex::new(r"watch(mdh|dirty).to/.+").unwrap().is_match(url)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
-        // WatchMDH has no playlists.
-        Ok(false)
-    }
-
-    fn find_video_title<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        webdriver_port: u16,
-    ) -> Result<String> {
-        let _not_used = get_video_info(video, url, webdriver_port)?;
-        let video_info_html = Html::parse_document(video.info.as_str());
-
-        let title_selector = Selector::parse(r#"meta[property="og:title"]"#).unwrap();
-        let title_elem = video_info_html.select(&title_selector).next().unwrap();
-        let title_contents = title_elem.value().attr("content").unwrap();
-
-        Ok(title_contents.to_string())
-    }
-
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        let _not_used = get_video_info(video, url, _webdriver_port)?;
-        let video_info_html = Html::parse_document(video.info.as_str());
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
+        let body = ctx.browse(url, &[])?;
+        let video_info_html = Html::parse_document(body.as_str());
 
         let url_selector = Selector::parse("video").unwrap();
-        let url_elem = video_info_html.select(&url_selector).next().unwrap();
-        let url_contents = url_elem.value().attr("src").unwrap();
+        let Some(url_contents) = video_info_html
+            .select(&url_selector)
+            .next()
+            .and_then(|url_elem| url_elem.value().attr("src"))
+        else {
+            return Ok(None);
+        };
 
-        Ok(url_contents.to_string())
-    }
-
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        webdriver_port: u16,
-    ) -> Result<bool> {
-        let _video_info = get_video_info(video, url, webdriver_port);
-        Ok(!video.info.is_empty())
+        let title_selector = Selector::parse(r#"meta[property="og:title"]"#).unwrap();
+        let title = video_info_html
+            .select(&title_selector)
+            .next()
+            .and_then(|title_elem| title_elem.value().attr("content"))
+            .unwrap_or("")
+            .to_string();
+
+        // The video ID is the last part of the URL path:
+        let id = url.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+
+        Ok(Some(VideoMetadata {
+            id: id.to_string(),
+            title,
+            // WatchMDH has no playlists.
+            formats: vec![VideoFormat {
+                id: "http".to_string(),
+                url: url_contents.to_string(),
+                ext: "mp4".to_string(),
+                is_playlist: false,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }))
     }
 
     fn display_name(&self) -> String {
         "WatchMDH".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        _video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        Ok("mp4".to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         true
     }
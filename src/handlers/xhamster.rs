@@ -16,194 +16,89 @@
 // Yet Another Youtube Down Loader
 // - xHamster handler -
 
-use crate::definitions::SiteDefinition;
+use crate::definitions::{HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
 
-use anyhow::{anyhow, Result};
-use nom::Finish;
+use anyhow::Result;
 use regex::Regex;
 use scraper::{Html, Selector};
 use url::Url;
 
-use crate::VIDEO;
-
-fn get_video_info(video: &mut VIDEO, url: &str) -> Result<bool> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // It will contain the whole body for now.
-        let local_url = url.to_owned();
-        let mut agent = ureq::agent();
-
-        let url_p = Url::parse(url)?;
-        if let Some(env_proxy) = env_proxy::for_url(&url_p).host_port() {
-            // Use a proxy:
-            let proxy = ureq::Proxy::new(format!("{}:{}", env_proxy.0, env_proxy.1));
-            agent = ureq::AgentBuilder::new().proxy(proxy.unwrap()).build();
-        }
-
-        video.info.push_str(
-            agent
-                .get(&local_url)
-                .call()
-                .expect("Could not go to the url")
-                .into_string()
-                .expect("Could not read the site source")
-                .as_str(),
-        );
-    }
+fn get_video_info(url: &str, ctx: &HandlerContext) -> Result<Html> {
+    // We need to fetch the video information first.
+    // It will contain the whole body for now.
+    let body = ctx.fetch(url)?;
+    Ok(Html::parse_document(&body))
+}
 
-    Ok(true)
+fn select_meta(document: &Html, property: &str) -> Option<String> {
+    let selector = Selector::parse(&format!(r#"meta[property="{}"]"#, property)).unwrap();
+    document
+        .select(&selector)
+        .next()?
+        .value()
+        .attr("content")
+        .map(str::to_string)
 }
 
-fn parse_playlist(document: &Html, agent: &ureq::Agent) -> Result<String> {
+fn parse_playlist(document: &Html, ctx: &HandlerContext) -> Result<Vec<VideoFormat>> {
     let url_selector = Selector::parse(r#"link[rel="preload"][as="fetch"]"#).unwrap();
-    let url_contents = document
+    let Some(url_contents) = document
         .select(&url_selector)
         .next()
-        .unwrap()
-        .value()
-        .attr("href")
-        .unwrap();
-    // let url_contents = url_elem.value().attr("href").unwrap();
-
-    let mut playlist_url = Url::parse(url_contents)?;
-    let get = agent.get(playlist_url.as_str());
-    let binding = get.call()?.into_string()?;
-    let playlist_text_b = binding.as_bytes();
-
-    // Parse the playlist:
-    let playlist = m3u8_rs::parse_media_playlist(playlist_text_b)
-        .finish()
-        .unwrap();
-
-    // Grab the last (= best) segment from the media playlist to find the video "playlist"
-    // (which contains all segments of the video):
-    let video_uri = &playlist.1.segments.last().ok_or("").unwrap().uri;
-
-    playlist_url
-        .path_segments_mut()
-        .unwrap()
-        .pop()
-        .push(video_uri);
-
-    Ok(playlist_url.to_string())
+        .and_then(|url_elem| url_elem.value().attr("href"))
+    else {
+        return Ok(vec![]);
+    };
+
+    // The playlist lists one video "playlist" (which contains
+    // all segments of the video) per variant:
+    crate::download::playlist_formats(ctx, url_contents)
 }
 
 // Implement the site definition:
 struct XHamsterHandler;
 impl SiteDefinition for XHamsterHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, _ctx: &HandlerContext) -> bool {
         Regex::new(r"xhamster.com/.+").unwrap().is_match(url)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
-        // xHamster has playlists.
-        Ok(true)
-    }
-
-    fn find_video_title<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<String> {
-        let _not_used = get_video_info(video, url)?;
-        let video_info_html = Html::parse_document(video.info.as_str());
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
+        let video_info_html = get_video_info(url, ctx)?;
 
         let h1_selector = Selector::parse("h1").unwrap();
-        let text = video_info_html.select(&h1_selector).next();
-
-        let result = match text {
-            Some(txt) => txt.text().collect(),
-            None => return Err(anyhow!("Could not extract the video title.")),
-        };
-
-        Ok(result)
-    }
-
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        let _not_used = get_video_info(video, url)?;
-        let video_info_html = Html::parse_document(video.info.as_str());
-
-        let agent = match crate::from_env_proxy(url) {
-            Some(agent) => agent,
-            None => ureq::agent(),
-        };
-
-        // let mut agent = ureq::agent();
-        // let url_p = Url::parse(url)?;
-        // if let Some(env_proxy) = env_proxy::for_url(&url_p).host_port() {
-        //     // Use a proxy:
-        //     let proxy = ureq::Proxy::new(format!("{}:{}", env_proxy.0, env_proxy.1));
-        //     agent = ureq::AgentBuilder::new().proxy(proxy.unwrap()).build();
-        // }
-
-        // Find the playlist first:
-        parse_playlist(&video_info_html, &agent)
-
-        // let url_selector = Selector::parse(r#"link[rel="preload"][as="fetch"]"#).unwrap();
-        // let url_contents = video_info_html
-        //     .select(&url_selector)
-        //     .next()
-        //     .unwrap()
-        //     .value()
-        //     .attr("href")
-        //     .unwrap();
-        // // let url_contents = url_elem.value().attr("href").unwrap();
-        //
-        // let mut playlist_url = Url::parse(url_contents)?;
-        // let get = agent.get(playlist_url.as_str());
-        // let binding = get.call()?.into_string()?;
-        // let playlist_text_b = binding.as_bytes();
-        //
-        // // Parse the playlist:
-        // let playlist = m3u8_rs::parse_media_playlist(playlist_text_b)
-        //     .finish()
-        //     .unwrap();
-        //
-        // // Grab the last (= best) segment from the media playlist to find the video "playlist"
-        // // (which contains all segments of the video):
-        // let video_uri = &playlist.1.segments.last().ok_or("").unwrap().uri;
-        //
-        // // xHamster uses relative URIs in its playlists, so we'll only need to replace
-        // // the last URL segment:
-        // playlist_url
-        //     .path_segments_mut()
-        //     .unwrap()
-        //     .pop()
-        //     .push(video_uri);
-        // Ok(playlist_url.to_string())
-    }
+        let title = video_info_html
+            .select(&h1_selector)
+            .next()
+            .map(|txt| txt.text().collect())
+            .unwrap_or_default();
+
+        // Find the playlist first. xHamster has playlists.
+        let formats = parse_playlist(&video_info_html, ctx)?;
+        if formats.is_empty() {
+            return Ok(None);
+        }
 
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<bool> {
-        let _video_info = get_video_info(video, url);
-        Ok(!video.info.is_empty())
+        // The video ID is the last part of the URL path:
+        let id = Url::parse(url)?
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or("")
+            .to_string();
+
+        Ok(Some(VideoMetadata {
+            id,
+            title,
+            thumbnail: select_meta(&video_info_html, "og:image"),
+            description: select_meta(&video_info_html, "og:description"),
+            formats,
+            ..Default::default()
+        }))
     }
 
     fn display_name(&self) -> String {
         "xHamster".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        _video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        Ok("ts".to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         false
     }
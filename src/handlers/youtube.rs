@@ -16,12 +16,13 @@
 // Yet Another Youtube Down Loader
 // - YouTube and Invidious handler -
 
-use crate::definitions::SiteDefinition;
-use crate::VIDEO;
+use crate::definitions::{Chapter, HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
+use crate::prelude::split_codecs;
 
 use anyhow::Result;
 use regex::Regex;
 use scraper::{Html, Selector};
+use serde_json::Value;
 use std::{
     env,
     sync::{LazyLock, RwLock},
@@ -39,7 +40,12 @@ static ID_REGEX: LazyLock<RwLock<Regex>> = LazyLock::new(|| {
     std::sync::RwLock::new(Regex::new(r"(?:v=|\.be/|shorts/)(.*?)(&.*)*$").unwrap())
 });
 
-fn get_invidious_instance() -> String {
+fn get_invidious_instance(ctx: &HandlerContext) -> String {
+    // An instance set by the caller wins over the environment:
+    if let Some(instance) = &ctx.invidious_instance {
+        return instance.to_string();
+    }
+
     let invidious_env = env::var("YAYDL_INVIDIOUS_INSTANCE");
     if invidious_env.is_ok() {
         invidious_env.unwrap_or(INVIDIOUS_INSTANCE.to_string())
@@ -48,178 +54,183 @@ fn get_invidious_instance() -> String {
     }
 }
 
-// PERF: This could just be an Into<String> or Into<Str> or Into<Url> or AsRef<str> or something.
-// and replace the usage of the function with video.info.as_str() or something.
-// and you don't have a large struct being parsed & no need to be mutable either
-//
-// fn get_video_info(video: impl Into<String> , url: &str) -> Result<Html> {
-// let video = video.into();
-// if video.is_empty() {
-//
-// etc..
-//
-fn get_video_info(video: &mut VIDEO, url: &str) -> Result<Html> {
-    if video.info.is_empty() {
-        // We need to fetch the video information first.
-        // It will contain the whole body for now.
-        // Exchange the URL -> Invidious:
-        let id = ID_REGEX
-            .read()
-            .unwrap()
-            .captures(url)
-            .unwrap()
-            .get(1)
-            .unwrap()
-            .as_str();
-        // let id = id_regex.captures(url).unwrap().get(1).unwrap().as_str();
-
-        let local_url = format!("{}/watch?v={}", get_invidious_instance(), id).to_owned();
-
-        // Initialize the agent:
-        // let mut agent = ureq::agent();
-        // if let Some(env_proxy) = env_proxy::for_url(&Url::parse(&local_url)?).host_port() {
-        //     // if let Some(env_proxy) = env_proxy::for_url(&url_p).host_port() {
-        //     // Use a proxy:
-        //     let proxy = ureq::Proxy::new(format!("{}:{}", env_proxy.0, env_proxy.1));
-        //     agent = ureq::AgentBuilder::new().proxy(proxy.unwrap()).build();
-        // }
-
-        video.info.push_str(
-            crate::from_env_proxy(&local_url)
-                .unwrap_or(ureq::agent())
-                .get(&local_url)
-                .call()?
-                .into_string()?
-                .as_str(),
-        );
+fn get_video_info(url: &str, ctx: &HandlerContext) -> Result<(String, Html)> {
+    // Exchange the URL -> Invidious:
+    let id = ID_REGEX
+        .read()
+        .unwrap()
+        .captures(url)
+        .and_then(|c| c.get(1))
+        .map_or("", |m| m.as_str())
+        .to_string();
+
+    let local_url = format!("{}/watch?v={}", get_invidious_instance(ctx), id).to_owned();
+    let body = ctx.fetch(&local_url)?;
+
+    Ok((id, Html::parse_document(&body)))
+}
+
+fn select_text(document: &Html, selector: &str) -> Option<String> {
+    let selector = Selector::parse(selector).unwrap();
+    let text = document
+        .select(&selector)
+        .next()?
+        .text()
+        .collect::<String>()
+        .trim()
+        .to_string();
+
+    (!text.is_empty()).then_some(text)
+}
+
+fn select_meta(document: &Html, property: &str) -> Option<String> {
+    let selector = Selector::parse(&format!(r#"meta[property="{}"]"#, property)).unwrap();
+    document
+        .select(&selector)
+        .next()?
+        .value()
+        .attr("content")
+        .map(str::to_string)
+}
+
+fn height_from_label(label: &str) -> Option<u64> {
+    match label {
+        "tiny" => Some(144),
+        "small" => Some(240),
+        "medium" => Some(360),
+        "large" => Some(480),
+        _ => label
+            .trim_start_matches("hd")
+            .trim_end_matches('p')
+            .parse()
+            .ok(),
     }
-    Ok(Html::parse_document(&video.info))
 }
 
-// pub fn from_env_proxy(url: impl AsRef<str>) -> Option<ureq::Agent> {
-//     if let Some(env_proxy) = env_proxy::for_url(&Url::parse(url.as_ref()).unwrap()).host_port() {
-//         // Use a proxy:
-//         let proxy = ureq::Proxy::new(format!("{}:{}", env_proxy.0, env_proxy.1));
-//         let agent = ureq::AgentBuilder::new().proxy(proxy.unwrap()).build();
-//         Some(agent)
-//     } else {
-//         None
-//     }
-// }
+fn parse_upload_date(published: &str) -> Option<String> {
+    // Invidious writes e.g. "Shared Nov 23, 2009":
+    let date_re = Regex::new(r"(?P<M>[A-Z][a-z]{2}) (?P<D>\d{1,2}), (?P<Y>\d{4})").unwrap();
+    let captures = date_re.captures(published)?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|m| *m == &captures["M"])?
+        + 1;
+
+    Some(format!(
+        "{}{:02}{:02}",
+        &captures["Y"],
+        month,
+        captures["D"].parse::<u32>().ok()?
+    ))
+}
 
 // Implement the site definition:
 struct YouTubeHandler;
 impl SiteDefinition for YouTubeHandler {
-    fn can_handle_url<'a>(&'a self, url: &'a str) -> bool {
+    fn can_handle_url<'a>(&'a self, url: &'a str, _ctx: &HandlerContext) -> bool {
         Regex::new(r"invidious\.|(?:www\.)?youtu(?:be\.com|\.be)/")
             .unwrap()
             .is_match(url)
     }
 
-    fn is_playlist<'a>(&'a self, _url: &'a str, _webdriver_port: u16) -> Result<bool> {
-        // Left as an exercise to the user. TBD.
-        Ok(false)
-    }
+    fn extract<'a>(&'a self, url: &'a str, ctx: &HandlerContext) -> Result<Option<VideoMetadata>> {
+        let (id, video_info) = get_video_info(url, ctx)?;
 
-    fn find_video_title<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<String> {
-        let video_info = get_video_info(video, url)?;
+        // Invidious puts the length of the video into a JSON blob:
+        let duration = select_text(&video_info, "script#video_data")
+            .and_then(|json| serde_json::from_str::<Value>(&json).ok())
+            .and_then(|data| data["length_seconds"].as_u64());
 
-        let title_selector = Selector::parse(r#"meta[property="og:title"]"#).unwrap();
-        let title_elem = video_info.select(&title_selector).next().unwrap();
-        let title_contents = title_elem.value().attr("content").unwrap();
+        let mut formats = vec![];
 
-        Ok(title_contents.to_string())
-    }
-
-    fn find_video_direct_url<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        let video_info = get_video_info(video, url)?;
-
-        let mut url_to_choose = "".to_string();
-
-        // Find the least horrible format:
+        // Invidious lists the formats from the best to the worst one:
         let quality_selector = Selector::parse(r#"source"#).unwrap();
-        let quality_iter = video_info.select(&quality_selector);
-
-        // Please - we have language tools to not have to do these things in Rust;
-        // mutability is (esp when a single variable and very generally) a code smell.
-        let mut last_vq: String = String::new();
-
-        for source in quality_iter {
-            // The highest quality wins.
-            let this_tag = source;
-            let this_vq = this_tag.value().attr("label").unwrap_or("");
-
-            let is_same_or_better_video = this_vq != last_vq && last_vq != "medium";
-
-            // Try to download the best file.
-            if is_same_or_better_video {
-                let this_mimetype = this_tag.value().attr("type").unwrap();
-
-                // Example: type="video/mp4; codecs=&quot;avc1.64001F, mp4a.40.2&quot;"
-                // Fetch the video/mp4 substring:
-                let mut mime_split = this_mimetype.split(";");
-                video.mime = mime_split.next().unwrap().to_string();
+        let itag_re = Regex::new(r"itag=(\d+)").unwrap();
+        for source in video_info.select(&quality_selector) {
+            let (Some(this_mimetype), Some(relative_url)) =
+                (source.value().attr("type"), source.value().attr("src"))
+            else {
+                continue;
+            };
+
+            // Example: type="video/mp4; codecs=&quot;avc1.64001F, mp4a.40.2&quot;"
+            // Fetch the video/mp4 substring and the codecs:
+            let mut mime_split = this_mimetype.split(";");
+            let mime = mime_split.next().unwrap_or("").trim();
+            let (vcodec, acodec) = mime_split
+                .find_map(|param| param.trim().strip_prefix("codecs="))
+                .map_or((None, None), |codecs| {
+                    split_codecs(codecs.trim_matches('"'))
+                });
+
+            let ext = if mime.contains("/webm") {
+                "webm"
+            } else if mime.contains("audio/mp4") {
+                "m4a"
+            } else {
+                "mp4"
+            };
+
+            // The label is either a quality name (e.g. "hd720") or a bitrate (e.g. "128k"):
+            let label = source.value().attr("label").unwrap_or("");
+            let itag = itag_re
+                .captures(relative_url)
+                .map_or(label.to_string(), |c| c[1].to_string());
+
+            formats.push(VideoFormat {
+                id: itag,
+                url: format!("{}{}", get_invidious_instance(ctx), relative_url),
+                ext: ext.to_string(),
+                height: height_from_label(label),
+                vcodec,
+                acodec,
+                bitrate: label.strip_suffix('k').and_then(|b| b.parse().ok()),
+                audio_only: mime.starts_with("audio/"),
+                ..Default::default()
+            });
+        }
 
-                let relative_url = this_tag.value().attr("src").unwrap();
-                url_to_choose = format!("{}{}", get_invidious_instance(), relative_url);
+        formats.reverse();
 
-                // Only update last_vq if it's the best format yet.
-                last_vq = String::from(this_vq);
-            }
-        }
+        // Invidious also has a DASH manifest with the separate video
+        // and audio streams of better qualities. It is optional though:
+        let manifest_url = format!(
+            "{}/api/manifest/dash/id/{}?local=true",
+            get_invidious_instance(ctx),
+            id
+        );
+        formats.extend(crate::dash::manifest_formats(ctx, &manifest_url).unwrap_or_default());
 
-        if url_to_choose.is_empty() {
-            Err(anyhow::Error::msg(
-                "Could not find a working video - aborting.".to_string(),
-            ))
-        } else {
-            Ok(url_to_choose)
+        if formats.is_empty() {
+            return Ok(None);
         }
-    }
 
-    fn does_video_exist<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        url: &'a str,
-        _webdriver_port: u16,
-    ) -> Result<bool> {
-        let _video_info = get_video_info(video, url);
-        Ok(!video.info.is_empty())
+        // The description lists the chapters, if there are any:
+        let description = select_text(&video_info, "#descriptionWrapper");
+        let chapters = description
+            .as_deref()
+            .map_or(vec![], Chapter::from_description);
+
+        Ok(Some(VideoMetadata {
+            id,
+            title: select_meta(&video_info, "og:title").unwrap_or_default(),
+            uploader: select_text(&video_info, "#channel-name"),
+            duration,
+            thumbnail: select_meta(&video_info, "og:image"),
+            description,
+            upload_date: select_text(&video_info, "#published-date")
+                .and_then(|published| parse_upload_date(&published)),
+            chapters,
+            formats,
+        }))
     }
 
     fn display_name(&self) -> String {
         "Invidious".to_string()
     }
 
-    fn find_video_file_extension<'a>(
-        &'a self,
-        video: &'a mut VIDEO,
-        _url: &'a str,
-        _webdriver_port: u16,
-        _onlyaudio: bool,
-    ) -> Result<String> {
-        // By this point, we have already filled VIDEO_MIME. Let's just use that.
-        let mut ext = "mp4";
-        if video.mime.contains("/webm") {
-            ext = "webm";
-        } else if video.mime.contains("audio/mp4") {
-            ext = "m4a";
-        }
-
-        Ok(ext.to_string())
-    }
-
     fn web_driver_required(&self) -> bool {
         false
     }
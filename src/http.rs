@@ -0,0 +1,350 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - http.rs file -
+
+// Every request yaydl sends goes through the one HttpClient of the
+// Downloader, so the network settings apply to the handlers and the
+// downloads alike.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use cookie_store::{CookieStore, RawCookie};
+use url::Url;
+
+use crate::cookies;
+
+const DEFAULT_USER_AGENT: &str = concat!("yaydl/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_REDIRECTS: u32 = 5;
+
+/// The HTTP settings shared by all requests of a Downloader.
+///
+/// Cloning is cheap: all clones share the same connections.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    settings: Arc<Settings>,
+    // One agent per proxy, so the connections are kept alive between
+    // requests (None for a direct connection):
+    agents: Arc<Mutex<HashMap<Option<String>, ureq::Agent>>>,
+    // The cookies from the cookie file and the ones the sites have set
+    // since, shared by all agents:
+    cookies: Option<Arc<Mutex<CookieStore>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    proxy: Option<String>,
+    user_agent: String,
+    headers: Vec<(String, String)>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    max_redirects: u32,
+    // The Netscape cookie jar to read (and maybe write back):
+    cookie_file: Option<PathBuf>,
+}
+
+/// Builds an HttpClient, see HttpClient::builder().
+#[derive(Debug, Clone)]
+pub struct HttpClientBuilder {
+    settings: Settings,
+}
+
+impl Default for HttpClientBuilder {
+    fn default() -> Self {
+        Self {
+            settings: Settings {
+                proxy: None,
+                user_agent: DEFAULT_USER_AGENT.to_string(),
+                headers: vec![],
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                read_timeout: DEFAULT_READ_TIMEOUT,
+                max_redirects: DEFAULT_MAX_REDIRECTS,
+                cookie_file: None,
+            },
+        }
+    }
+}
+
+impl HttpClientBuilder {
+    /// Sets the proxy to use instead of the one from the environment,
+    /// e.g. "http://localhost:8080" or "socks5://localhost:1080".
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.settings.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Sets the User-Agent header of all requests.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.settings.user_agent = user_agent.into();
+        self
+    }
+
+    /// Adds a header which is sent with all requests.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.settings.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets how long to wait for a connection (default: 30 seconds).
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait for more data from a server (default: 60 seconds).
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.read_timeout = timeout;
+        self
+    }
+
+    /// Sets how many redirects to follow (default: 5, 0 to follow none).
+    pub fn max_redirects(mut self, count: u32) -> Self {
+        self.settings.max_redirects = count;
+        self
+    }
+
+    /// Sends the cookies from a Netscape cookie file (e.g. "cookies.txt")
+    /// with all requests. The file does not need to exist yet, see
+    /// HttpClient::save_cookies().
+    pub fn cookies(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.cookie_file = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> Result<HttpClient> {
+        // Better fail now than with the first request:
+        if let Some(proxy) = &self.settings.proxy {
+            ureq::Proxy::new(proxy).map_err(|e| anyhow!("Invalid proxy \"{}\": {}", proxy, e))?;
+        }
+
+        let cookies = match &self.settings.cookie_file {
+            Some(path) => Some(Arc::new(Mutex::new(cookies::load(path)?))),
+            None => None,
+        };
+
+        Ok(HttpClient {
+            settings: Arc::new(self.settings),
+            agents: Arc::default(),
+            cookies,
+        })
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient {
+            settings: Arc::new(HttpClientBuilder::default().settings),
+            agents: Arc::default(),
+            cookies: None,
+        }
+    }
+}
+
+impl HttpClient {
+    pub fn builder() -> HttpClientBuilder {
+        HttpClientBuilder::default()
+    }
+
+    /// Returns the ureq agent to use for <url>.
+    pub fn agent(&self, url: &str) -> Result<ureq::Agent> {
+        let proxy = match &self.settings.proxy {
+            Some(proxy) => Some(proxy.clone()),
+            None => Url::parse(url)
+                .ok()
+                .and_then(|url| env_proxy::for_url(&url).host_port())
+                .map(|(host, port)| format!("{}:{}", host, port)),
+        };
+
+        let mut agents = self.agents.lock().unwrap();
+        if let Some(agent) = agents.get(&proxy) {
+            return Ok(agent.clone());
+        }
+
+        let agent = self.build_agent(proxy.as_deref())?;
+        agents.insert(proxy, agent.clone());
+        Ok(agent)
+    }
+
+    /// Returns a GET request for <url>.
+    pub fn get(&self, url: &str) -> Result<ureq::Request> {
+        Ok(self.agent(url)?.get(url))
+    }
+
+    /// Writes the cookies back into the cookie file, including the ones
+    /// the sites have set or changed since. Does nothing without a cookie file.
+    pub fn save_cookies(&self) -> Result<()> {
+        let (Some(path), Some(jar)) = (&self.settings.cookie_file, &self.cookies) else {
+            return Ok(());
+        };
+
+        cookies::save(path, jar.lock().unwrap().iter_any())
+    }
+
+    fn build_agent(&self, proxy: Option<&str>) -> Result<ureq::Agent> {
+        let settings = &self.settings;
+        let mut builder = ureq::AgentBuilder::new()
+            .user_agent(&settings.user_agent)
+            .timeout_connect(settings.connect_timeout)
+            .timeout_read(settings.read_timeout)
+            .redirects(settings.max_redirects);
+
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(ureq::Proxy::new(proxy)?);
+        }
+
+        // (A "Cookie" header of the user wins over the jar.)
+        if !settings.headers.is_empty() {
+            builder = builder.middleware(ExtraHeaders(settings.headers.clone()));
+        }
+
+        if let Some(jar) = &self.cookies {
+            builder = builder.middleware(CookieJar(jar.clone()));
+        }
+
+        Ok(builder.build())
+    }
+}
+
+// Adds the headers of the settings to each request:
+struct ExtraHeaders(Vec<(String, String)>);
+
+impl ureq::Middleware for ExtraHeaders {
+    fn handle(
+        &self,
+        mut request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        // Headers of the request itself (e.g. "Range") come first:
+        for (name, value) in &self.0 {
+            if !request.has(name) {
+                request = request.set(name, value);
+            }
+        }
+        next.handle(request)
+    }
+}
+
+// Sends the cookies of the jar with each request and keeps the ones the
+// response sets. (Only the final response of a redirect is seen here.)
+struct CookieJar(Arc<Mutex<CookieStore>>);
+
+impl ureq::Middleware for CookieJar {
+    fn handle(
+        &self,
+        mut request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        if let Ok(url) = Url::parse(request.url()) {
+            let header = self
+                .0
+                .lock()
+                .unwrap()
+                .get_request_values(&url)
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("; ");
+            if !header.is_empty() && !request.has("Cookie") {
+                request = request.set("Cookie", &header);
+            }
+        }
+
+        let result = next.handle(request);
+
+        // Error pages (e.g. a login form with a 403) set cookies, too:
+        let response = match &result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(_) => return result,
+        };
+        if let Ok(url) = Url::parse(response.get_url()) {
+            let cookies = response
+                .all("Set-Cookie")
+                .into_iter()
+                .filter_map(|value| RawCookie::parse(value.to_string()).ok());
+            self.0.lock().unwrap().store_response_cookies(cookies, &url);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::process;
+    use std::thread;
+
+    // Serves <count> requests which set the cookie "b=2", returns the base
+    // URL and the Cookie headers which the requests came with.
+    fn serve(count: usize) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let mut cookies = vec![];
+            for mut stream in listener.incoming().flatten().take(count) {
+                let mut request = [0; 4096];
+                let n = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]).to_string();
+                cookies.push(
+                    request
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Cookie: "))
+                        .unwrap_or_default()
+                        .to_string(),
+                );
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nSet-Cookie: b=2\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            }
+            cookies
+        });
+
+        (base, server)
+    }
+
+    #[test]
+    fn agents_share_one_cookie_jar() {
+        let path = std::env::temp_dir().join(format!("yaydl-{}-http.txt", process::id()));
+        fs::write(&path, "127.0.0.1\tFALSE\t/\tFALSE\t0\ta\t1\n").unwrap();
+
+        let (base, server) = serve(2);
+        let http = HttpClient::builder().cookies(&path).build().unwrap();
+        http.get(&base).unwrap().call().unwrap();
+
+        // A fresh agent (as for another proxy) sees the new cookie, too:
+        http.agents.lock().unwrap().clear();
+        http.get(&base).unwrap().call().unwrap();
+        let cookies = server.join().unwrap();
+        assert_eq!(cookies[0], "a=1");
+        let mut sent: Vec<_> = cookies[1].split("; ").collect();
+        sent.sort();
+        assert_eq!(sent, ["a=1", "b=2"]);
+
+        http.save_cookies().unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(saved.contains("127.0.0.1\tFALSE\t/\tFALSE\t0\ta\t1\n"));
+        assert!(saved.contains("127.0.0.1\tFALSE\t/\tFALSE\t0\tb\t2\n"));
+    }
+}
@@ -0,0 +1,41 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - lib.rs file -
+
+mod cookies;
+mod dash;
+mod definitions;
+mod download;
+mod downloader;
+mod ffmpeg;
+mod handlers;
+mod http;
+mod prelude;
+mod processor;
+mod ratelimit;
+mod retry;
+mod sanitize;
+mod selector;
+mod template;
+mod webdriver;
+
+pub use crate::definitions::{Chapter, HandlerContext, SiteDefinition, VideoFormat, VideoMetadata};
+pub use crate::downloader::{
+    DownloadOutcome, DownloadedVideo, Downloader, DownloaderBuilder, Overwrites,
+};
+pub use crate::ffmpeg::AudioQuality;
+pub use crate::http::{HttpClient, HttpClientBuilder};
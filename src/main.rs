@@ -17,152 +17,229 @@
 // - main.rs file -
 
 mod args;
-mod definitions;
-mod download;
-mod ffmpeg;
-mod handlers;
-mod prelude;
-mod processor;
+mod printer;
 
 use crate::args::Args;
-use crate::prelude::{from_env_proxy, Printer};
-use crate::processor::{process_video, VideoProcessingResult};
-use anyhow::Result;
+use crate::printer::Printer;
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use processor::{InputOutputPaths, TargetFile};
-use std::borrow::Borrow;
-
-// usage:
-// let v = VIDEO{info: String::new(), title:String::new(), mime:String::new()};
-// println!("{:#?}",v);
-pub struct VIDEO {
-    info: String,
-    title: String,
-    mime: String,
-}
+use yaydl::{DownloadOutcome, Downloader, VideoMetadata};
 
-// Stop the linter from blowing up because of inventory::collect! macro
-#[allow(non_local_definitions)]
 fn main() -> Result<()> {
     // Argument parsing:
     let args = Args::parse();
 
-    inventory::collect!(&'static dyn definitions::SiteDefinition);
-
-    let mut video = VIDEO {
-        info: String::new(),
-        title: String::new(),
-        mime: String::new(),
-    };
+    // All requests share the same network settings:
+    let http = args.http_client()?;
+
+    let mut builder = Downloader::builder()
+        .only_audio(args.onlyaudio)
+        .audio_format(&args.audioformat)
+        .output_template(&args.outputtemplate)
+        .restrict_filenames(args.restrictfilenames)
+        .windows_filenames(args.windowsfilenames)
+        .overwrites(args.overwrites())
+        .keep_temp_file(args.keeptempfile)
+        .embed_metadata(args.embedmetadata)
+        .embed_thumbnail(args.embedthumbnail)
+        .verbose(args.verbose)
+        .concurrent_fragments(args.concurrentfragments)
+        .connections(args.connections)
+        .retries(args.retries)
+        .fragment_retries(args.fragmentretries)
+        .webdriver_port(args.parse_webdriver())
+        .http_client(http.clone());
+
+    if let Some(dir) = &args.paths {
+        builder = builder.output_dir(dir);
+    }
 
-    // Used in loop below:
-    let mut printer = Printer::new();
-    let in_url = &args.url;
-    let mut site_def_found = false;
+    if let Some(instance) = &args.invidious_instance {
+        builder = builder.invidious_instance(instance);
+    }
 
-    for handler in inventory::iter::<&dyn definitions::SiteDefinition> {
-        // Find a known handler for <in_url>:
-        if !handler.can_handle_url(in_url) {
-            continue;
-        }
+    if let Some(quality) = args.audioquality {
+        builder = builder.audio_quality(quality);
+    }
 
-        // This one is it.
-        site_def_found = true;
-        printer
-            .lock()
-            .add(format!("Fetching from {}.", handler.display_name()))
-            .flush();
-
-        if handler.web_driver_required() && args.parse_webdriver() == 0 {
-            // This handler would need a web driver, but none is supplied to yaydl.
-            printer.web_driver_req(handler.display_name());
-            continue;
-        }
+    if let Some(format) = &args.format {
+        builder = builder.format(format);
+    }
 
-        let processing_result = process_video(
-            handler.borrow(),
-            &args,
-            &mut video,
-            in_url,
-            args.parse_webdriver(),
-        )?;
+    if let Some(rate) = args.limitrate {
+        builder = builder.limit_rate(rate);
+    }
 
-        check_result(processing_result, &args, &mut printer)?;
+    if let Some(ffmpeg) = &args.ffmpeglocation {
+        builder = builder.ffmpeg_location(ffmpeg);
+    }
 
-        // Stop looking for other handlers:
-        break;
+    let downloader = builder.build()?;
+    let urls = args.urls()?;
+    if urls.is_empty() {
+        bail!("There are no URLs to download.");
     }
 
-    if !site_def_found {
-        println!(
-            "yaydl could not find a site definition that would satisfy {}. Exiting.",
-            in_url
-        );
+    let mut failures = run(&args, &downloader, &urls);
+
+    // Keep what the sites have told us, even if downloads failed:
+    if args.savecookies {
+        http.save_cookies()?;
     }
 
-    Ok(())
+    match failures.len() {
+        0 => Ok(()),
+        _ if urls.len() == 1 => Err(failures.remove(0).1),
+        count => Err(anyhow!("{} of {} URLs failed.", count, urls.len())),
+    }
 }
 
-fn check_result(
-    processing_result: VideoProcessingResult,
+// Processes all <urls> with the same <downloader>, so they share the
+// connections and the web driver session. A failed URL does not stop
+// the others. returns the failed URLs and why they failed.
+fn run<'a>(
     args: &Args,
-    printer: &mut Printer<String>,
-) -> Result<()> {
-    match processing_result {
-        VideoProcessingResult::VideoNotFound => {
-            println!("The video could not be found. Invalid link?");
-        }
-        VideoProcessingResult::TitleNotFound => {
-            println!("The video title could not be extracted. Invalid link?");
+    downloader: &Downloader,
+    urls: &'a [String],
+) -> Vec<(&'a str, anyhow::Error)> {
+    let mut printer = Printer::new();
+    let mut failures = vec![];
+    let mut skipped = 0;
+
+    for (i, url) in urls.iter().enumerate() {
+        if urls.len() > 1 {
+            printer
+                .add(format!("[{}/{}] {}\n", i + 1, urls.len(), url))
+                .flush();
         }
-        VideoProcessingResult::ReadyToProcess(ready) => {
-            if args.verbose {
-                println!("Starting the download.");
-            }
-
-            let url = ready.url.clone();
-            let targetfile = TargetFile::from(ready);
 
-            match targetfile.force_ffmpeg {
-                true => {
-                    targetfile.download_from_playlist(&url, args.verbose)?;
-                }
-                false => {
-                    targetfile.download(&url)?;
+        match process(args, downloader, &mut printer, url) {
+            Ok(true) => {}
+            Ok(false) => skipped += 1,
+            Err(e) => {
+                if urls.len() > 1 {
+                    eprintln!("Error: {:#}", e);
                 }
+                failures.push((url.as_str(), e));
             }
+        }
+    }
 
-            if (args.onlyaudio && targetfile.target_ext != *args.audioformat)
-                || targetfile.force_ffmpeg
-            {
-                if args.verbose {
-                    println!("Post-processing.");
-                }
+    // Nobody wants to scroll back through a long batch:
+    if urls.len() > 1 {
+        println!();
+        println!(
+            "{} of {} URLs done ({} already downloaded), {} failed.",
+            urls.len() - failures.len(),
+            urls.len(),
+            skipped,
+            failures.len()
+        );
+        for (url, reason) in &failures {
+            println!("  {}: {:#}", url, reason);
+        }
+    }
 
-                let mut paths_for = InputOutputPaths::from(&targetfile);
+    failures
+}
 
-                paths_for.to_audio_mut(args.onlyaudio, args.audioformat.clone());
+// Downloads (or lists the formats of) <in_url>. returns false if
+// there was nothing to do because the file exists already.
+fn process(
+    args: &Args,
+    downloader: &Downloader,
+    printer: &mut Printer<String>,
+    in_url: &str,
+) -> Result<bool> {
+    // Find a known handler for <in_url>:
+    let Some(handler) = downloader.handler_for(in_url) else {
+        return Err(unsupported(in_url));
+    };
 
-                if !args.keeptempfile {
-                    std::fs::remove_file(&targetfile.target_filename)?;
-                }
+    printer
+        .lock()
+        .add(format!("Fetching from {}.\n", handler.display_name()))
+        .flush();
 
-                printer
-                    .add(format!(
-                        "\"{}\" successfully downloaded.",
-                        paths_for.output_to_string()
-                    ))
-                    .flush();
-            } else {
-                printer
-                    .add(format!(
-                        "\"{}\" successfully downloaded.",
-                        &targetfile.target_filename
-                    ))
-                    .flush();
-            }
+    if args.listformats {
+        match downloader.extract_with(handler, in_url)? {
+            Some(metadata) => list_formats(&metadata),
+            None => bail!("The video could not be found. Invalid link?"),
         }
+        return Ok(true);
     }
 
-    Ok(())
+    match downloader.download_with(handler, in_url)? {
+        DownloadOutcome::Downloaded(video) => {
+            printer
+                .add(format!(
+                    "\"{}\" successfully downloaded.\n",
+                    video.path.to_string_lossy()
+                ))
+                .flush();
+        }
+        DownloadOutcome::UnsupportedUrl => return Err(unsupported(in_url)),
+        DownloadOutcome::WebDriverRequired(site) => {
+            bail!("{} requires a web driver installed and running as described in the README. Please tell yaydl which port to use (yaydl --webdriver <PORT>) and try again.", site);
+        }
+        DownloadOutcome::VideoNotFound => {
+            bail!("The video could not be found. Invalid link?");
+        }
+        DownloadOutcome::TitleNotFound => {
+            bail!("The video title could not be extracted. Invalid link?");
+        }
+        DownloadOutcome::FormatNotFound => {
+            bail!("No format matches your selection. Try --list-formats.");
+        }
+        DownloadOutcome::AlreadyDownloaded(path) => {
+            println!(
+                "\"{}\" has already been downloaded.",
+                path.to_string_lossy()
+            );
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn unsupported(url: &str) -> anyhow::Error {
+    anyhow!(
+        "yaydl could not find a site definition that would satisfy {}.",
+        url
+    )
+}
+
+fn list_formats(metadata: &VideoMetadata) {
+    println!();
+    println!(
+        "{:<16} {:<5} {:<12} {:<14} {:<14} {:>8}  NOTE",
+        "ID", "EXT", "RESOLUTION", "VCODEC", "ACODEC", "BITRATE"
+    );
+
+    for format in &metadata.formats {
+        let mut notes = vec![];
+        if format.video_only {
+            notes.push("video only");
+        }
+        if format.is_playlist {
+            notes.push("playlist");
+        }
+        if format.is_dash {
+            notes.push("dash");
+        }
+
+        println!(
+            "{:<16} {:<5} {:<12} {:<14} {:<14} {:>8}  {}",
+            format.id,
+            format.ext,
+            format.resolution(),
+            format.vcodec.as_deref().unwrap_or("-"),
+            format.acodec.as_deref().unwrap_or("-"),
+            format
+                .bitrate
+                .map_or("-".to_string(), |b| format!("{}k", b)),
+            notes.join(", ")
+        );
+    }
 }
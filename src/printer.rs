@@ -0,0 +1,39 @@
+use std::io::Write;
+
+/// Portal Printer to communicate with the user.
+pub struct Printer<S>
+where
+    S: AsRef<str>,
+{
+    buf: S,
+    stdout: std::io::Stdout,
+}
+
+impl Printer<String> {
+    pub fn new() -> Self {
+        let mut s = Self {
+            buf: String::new(),
+            stdout: std::io::stdout(),
+        };
+        s.lock();
+        s
+    }
+
+    pub fn lock(&mut self) -> &mut Self {
+        match self.stdout.lock().write_all(b"\x1b[?25l").as_ref() {
+            Ok(_) => self,
+            Err(_) => self,
+        }
+    }
+
+    pub fn add(&mut self, s: impl AsRef<str>) -> &mut Self {
+        self.buf.push_str(s.as_ref());
+        self
+    }
+
+    pub fn flush(&mut self) -> &mut Self {
+        self.stdout.write_all(self.buf.as_bytes()).unwrap();
+        self.buf.clear();
+        self
+    }
+}
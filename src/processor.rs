@@ -1,152 +1,423 @@
-use std::path::PathBuf;
-
-use crate::{definitions, Args};
-use anyhow::Result;
-
-pub struct ReadyToProcess {
-    pub title: String,
-    pub url: String,
-    pub ext: String,
-    pub is_playlist: bool,
-}
-
-pub enum VideoProcessingResult {
-    VideoNotFound,
-    TitleNotFound,
-    ReadyToProcess(ReadyToProcess),
-}
-
-pub fn process_video(
-    handler: &dyn definitions::SiteDefinition,
-    args: &Args,
-    video: &mut crate::VIDEO,
-    in_url: &str,
-    webdriverport: u16,
-) -> Result<VideoProcessingResult> {
-    if !handler.does_video_exist(video, in_url, webdriverport)? {
-        return Ok(VideoProcessingResult::VideoNotFound);
-    }
-
-    if args.verbose {
-        println!("The requested video was found. Processing...");
-    }
-
-    let vt = match handler.find_video_title(video, in_url, webdriverport) {
-        Err(_e) => "".to_string(),
-        Ok(title) => title,
-    };
-
-    if vt.is_empty() {
-        return Ok(VideoProcessingResult::TitleNotFound);
-    }
-
-    if args.verbose {
-        println!("Title: {}", vt);
-    }
-
-    Ok(VideoProcessingResult::ReadyToProcess(ReadyToProcess {
-        title: vt,
-        url: handler.find_video_direct_url(video, in_url, webdriverport, args.onlyaudio)?,
-        ext: handler.find_video_file_extension(video, in_url, webdriverport, args.onlyaudio)?,
-        is_playlist: handler.is_playlist(in_url, webdriverport).unwrap_or(false),
-    }))
-}
-
-impl From<VideoProcessingResult> for ReadyToProcess {
-    fn from(vpr: VideoProcessingResult) -> Self {
-        match vpr {
-            VideoProcessingResult::ReadyToProcess(rtp) => rtp,
-            _ => panic!("Expected ReadyToProcess"),
-        }
-    }
-}
-
-pub struct TargetFile {
-    // pub target_title: String,
-    // This should 1000% be an enum of types you're okay with supporting.
-    pub target_ext: String,
-    pub target_filename: String,
-    pub force_ffmpeg: bool,
-}
-
-impl From<ReadyToProcess> for TargetFile {
-    fn from(rtp: ReadyToProcess) -> Self {
-        let target_filename = format!(
-            "{}.{}",
-            rtp.title.trim().replace(
-                &['|', '\'', '\"', ':', '\'', '\\', '/', '?', '*'][..],
-                r#""#
-            ),
-            rtp.ext
-        )
-        .to_string();
-
-        match rtp.is_playlist {
-            true => Self {
-                // target_title: rtp.title,
-                target_ext: rtp.ext,
-                target_filename,
-                force_ffmpeg: true,
-            },
-            false => Self {
-                // target_title: rtp.title,
-                target_ext: rtp.ext,
-                target_filename,
-                force_ffmpeg: false,
-            },
-        }
-    }
-}
-
-impl TargetFile {
-    pub fn download_from_playlist(&self, url: impl AsRef<str>, verbosity: bool) -> Result<()> {
-        crate::download::download_from_playlist(url.as_ref(), &self.target_filename, verbosity)?;
-        Ok(())
-    }
-
-    pub fn download(&self, url: impl AsRef<str>) -> Result<()> {
-        crate::download::download(url.as_ref(), &self.target_filename)?;
-        Ok(())
-    }
-}
-
-pub struct InputOutputPaths {
-    pub input: PathBuf,
-    pub output: PathBuf,
-}
-
-impl From<&TargetFile> for InputOutputPaths {
-    fn from(tf: &TargetFile) -> Self {
-        Self {
-            input: PathBuf::from(&tf.target_filename),
-            output: PathBuf::from(&tf.target_filename),
-        }
-    }
-}
-
-impl InputOutputPaths {
-    pub fn set_ext_output(&mut self, ext: impl AsRef<str>) -> &mut Self {
-        self.output.set_extension(ext.as_ref());
-        self
-    }
-
-    pub fn to_audio_mut(&mut self, value: bool, ext: impl AsRef<str>) {
-        match value {
-            true => {
-                // clone the input so we can drop it here righnt after
-                let input = self.input.clone();
-                crate::ffmpeg::to_audio(&input, self.set_ext_output(ext).output.as_path());
-            }
-            false => {
-                let input = self.input.clone();
-                crate::ffmpeg::to_audio(&input, self.set_ext_output("mp4").output.as_path());
-            }
-        }
-    }
-}
-
-impl InputOutputPaths {
-    pub fn output_to_string(self) -> String {
-        self.output.to_string_lossy().to_string()
-    }
-}
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::definitions::{self, VideoFormat, VideoMetadata};
+use crate::download::DownloadOptions;
+use crate::ffmpeg::{audio_ext, can_copy_audio, AudioQuality, Embedding, Ffmpeg};
+use crate::sanitize::Sanitizer;
+use crate::template::render;
+use crate::Downloader;
+use anyhow::Result;
+
+pub struct ReadyToProcess {
+    pub metadata: VideoMetadata,
+    pub format: VideoFormat,
+    // The (audio) format to merge with <format>, if any.
+    pub merge_with: Option<VideoFormat>,
+}
+
+pub enum VideoProcessingResult {
+    VideoNotFound,
+    TitleNotFound,
+    FormatNotFound,
+    ReadyToProcess(Box<ReadyToProcess>),
+}
+
+pub fn process_video(
+    handler: &dyn definitions::SiteDefinition,
+    downloader: &Downloader,
+    in_url: &str,
+) -> Result<VideoProcessingResult> {
+    let metadata = match handler.extract(in_url, &downloader.context)? {
+        None => return Ok(VideoProcessingResult::VideoNotFound),
+        Some(metadata) => metadata,
+    };
+
+    if downloader.verbose {
+        println!("The requested video was found. Processing...");
+    }
+
+    if metadata.title.is_empty() {
+        return Ok(VideoProcessingResult::TitleNotFound);
+    }
+
+    if downloader.verbose {
+        println!("Title: {}", metadata.title);
+    }
+
+    let selector = &downloader.download_options.format_selector;
+    let (format, merge_with) = match selector.select_all(&metadata.formats).as_deref() {
+        Some([format]) => ((*format).clone(), None),
+        Some([format, merge_with]) => ((*format).clone(), Some((*merge_with).clone())),
+        _ => return Ok(VideoProcessingResult::FormatNotFound),
+    };
+
+    if downloader.verbose {
+        println!("Format: {} ({})", format.id, format.resolution());
+        if let Some(merge_with) = &merge_with {
+            println!(
+                "Merged with: {} ({})",
+                merge_with.id,
+                merge_with.resolution()
+            );
+        }
+    }
+
+    Ok(VideoProcessingResult::ReadyToProcess(Box::new(
+        ReadyToProcess {
+            metadata,
+            format,
+            merge_with,
+        },
+    )))
+}
+
+impl From<VideoProcessingResult> for ReadyToProcess {
+    fn from(vpr: VideoProcessingResult) -> Self {
+        match vpr {
+            VideoProcessingResult::ReadyToProcess(rtp) => *rtp,
+            _ => panic!("Expected ReadyToProcess"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TargetFile {
+    // pub target_title: String,
+    // This should 1000% be an enum of types you're okay with supporting.
+    pub target_ext: String,
+    pub target_filename: String,
+    pub force_ffmpeg: bool,
+    pub is_dash: bool,
+}
+
+// returns the container for merging a video and an audio stream.
+fn merged_ext(video_ext: &str, audio_ext: &str) -> String {
+    match (video_ext, audio_ext) {
+        ("webm", "webm") => "webm",
+        ("mp4", "m4a" | "mp4") => "mp4",
+        _ => "mkv",
+    }
+    .to_string()
+}
+
+// returns the value of the output template field <name> for <rtp>.
+fn template_field(rtp: &ReadyToProcess, target_ext: &str, name: &str) -> Option<String> {
+    let metadata = &rtp.metadata;
+    let format = &rtp.format;
+
+    match name {
+        "id" => Some(metadata.id.clone()),
+        "title" => Some(metadata.title.clone()),
+        "uploader" => metadata.uploader.clone(),
+        "upload_date" => metadata.upload_date.clone(),
+        "duration" => metadata.duration.map(|d| d.to_string()),
+        "ext" => Some(target_ext.to_string()),
+        "format_id" => Some(match &rtp.merge_with {
+            Some(merge_with) => format!("{}+{}", format.id, merge_with.id),
+            None => format.id.clone(),
+        }),
+        "width" => format.width.map(|w| w.to_string()),
+        "height" => format.height.map(|h| h.to_string()),
+        "resolution" => Some(format.resolution()),
+        _ => None,
+    }
+}
+
+impl TargetFile {
+    // returns the target file for <rtp>, named after the output <template>.
+    pub fn new(rtp: ReadyToProcess, template: &str, sanitizer: &Sanitizer) -> Result<Self> {
+        let target_ext = match &rtp.merge_with {
+            Some(merge_with) => merged_ext(&rtp.format.ext, &merge_with.ext),
+            None => rtp.format.ext.clone(),
+        };
+
+        let target_filename = render(template, sanitizer, |name| {
+            template_field(&rtp, &target_ext, name)
+        })?;
+
+        // Merging produces the target container already:
+        if rtp.merge_with.is_some() {
+            return Ok(Self {
+                target_ext,
+                target_filename,
+                force_ffmpeg: false,
+                is_dash: false,
+            });
+        }
+
+        Ok(match rtp.format.is_playlist {
+            true => Self {
+                // target_title: rtp.title,
+                target_ext,
+                target_filename,
+                force_ffmpeg: true,
+                is_dash: false,
+            },
+            false => Self {
+                // target_title: rtp.title,
+                target_ext,
+                target_filename,
+                force_ffmpeg: false,
+                is_dash: rtp.format.is_dash,
+            },
+        })
+    }
+
+    pub fn in_dir(mut self, dir: Option<&Path>) -> Self {
+        if let Some(dir) = dir {
+            self.target_filename = dir
+                .join(&self.target_filename)
+                .to_string_lossy()
+                .to_string();
+        }
+        self
+    }
+
+    pub fn download_from_playlist(
+        &mut self,
+        agent: &ureq::Agent,
+        url: impl AsRef<str>,
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        let filename = crate::download::download_from_playlist(
+            agent,
+            url.as_ref(),
+            &self.target_filename,
+            options,
+        )?;
+
+        // fMP4 playlists are MP4 files already, no need to convert them:
+        if filename != self.target_filename {
+            self.target_filename = filename;
+            self.target_ext = "mp4".to_string();
+            self.force_ffmpeg = false;
+        }
+        Ok(())
+    }
+
+    pub fn download_from_manifest(
+        &self,
+        agent: &ureq::Agent,
+        url: impl AsRef<str>,
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        crate::dash::download_from_manifest(agent, url.as_ref(), &self.target_filename, options)?;
+        Ok(())
+    }
+
+    pub fn download(
+        &self,
+        agent: &ureq::Agent,
+        url: impl AsRef<str>,
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        crate::download::download(agent, url.as_ref(), &self.target_filename, options)?;
+        Ok(())
+    }
+
+    // Downloads <url> with the method that fits the file.
+    pub fn fetch(
+        &mut self,
+        agent: &ureq::Agent,
+        url: impl AsRef<str>,
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        match (self.force_ffmpeg, self.is_dash) {
+            (true, _) => self.download_from_playlist(agent, url, options),
+            (false, true) => self.download_from_manifest(agent, url, options),
+            (false, false) => self.download(agent, url, options),
+        }
+    }
+
+    // returns this file with the number <n> added to its name,
+    // e.g. "Title (1).mp4" for "Title.mp4".
+    pub fn numbered(&self, n: usize) -> TargetFile {
+        let path = Path::new(&self.target_filename);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let target_filename = path
+            .with_file_name(format!("{} ({}).{}", stem, n, self.target_ext))
+            .to_string_lossy()
+            .to_string();
+
+        Self {
+            target_ext: self.target_ext.clone(),
+            target_filename,
+            force_ffmpeg: self.force_ffmpeg,
+            is_dash: self.is_dash,
+        }
+    }
+
+    // returns the file for the stream <format> of a merged download,
+    // e.g. "Title.f137.mp4" next to "Title.mkv".
+    pub fn part(&self, format: &VideoFormat) -> TargetFile {
+        let target_filename = Path::new(&self.target_filename)
+            .with_extension(format!("f{}.{}", format.id, format.ext))
+            .to_string_lossy()
+            .to_string();
+
+        Self {
+            target_ext: format.ext.clone(),
+            target_filename,
+            force_ffmpeg: format.is_playlist,
+            is_dash: format.is_dash,
+        }
+    }
+}
+
+pub struct InputOutputPaths {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+impl From<PathBuf> for InputOutputPaths {
+    fn from(path: PathBuf) -> Self {
+        Self {
+            input: path.clone(),
+            output: path,
+        }
+    }
+}
+
+impl InputOutputPaths {
+    pub fn set_ext_output(&mut self, ext: impl AsRef<str>) -> &mut Self {
+        self.output.set_extension(ext.as_ref());
+        self
+    }
+
+    // Runs <step> on the input file.
+    pub fn run(&mut self, step: &PostProcessingStep, ffmpeg: &Ffmpeg) -> Result<()> {
+        let input = self.input.clone();
+        let output = self.set_ext_output(step.ext()).output.as_path();
+
+        match step {
+            PostProcessingStep::Remux(_) => ffmpeg.remux(&input, output),
+            PostProcessingStep::ExtractAudio {
+                format, quality, ..
+            } => ffmpeg.to_audio(&input, output, format, *quality),
+            PostProcessingStep::CopyAudio(_) => ffmpeg.copy_audio(&input, output),
+            PostProcessingStep::Embed { ext, embedding } => {
+                // ffmpeg cannot write into its input file:
+                let tempfile = output.with_extension(format!("temp.{}", ext));
+                ffmpeg.embed(&input, &tempfile, embedding)?;
+                Ok(std::fs::rename(tempfile, output)?)
+            }
+            PostProcessingStep::Transcode(_) => ffmpeg.transcode(&input, output),
+        }
+    }
+}
+
+// Codecs which fit into an MP4 container:
+const MP4_CODECS: [&str; 13] = [
+    "avc1", "avc3", "hvc1", "hev1", "av01", "vp09", "mp4v", "mp4a", "ac-3", "ec-3", "opus", "flac",
+    "mp3",
+];
+
+fn fits_into_mp4(codec: Option<&str>) -> bool {
+    codec.is_none_or(|codec| {
+        let codec = codec.to_lowercase();
+        MP4_CODECS.iter().any(|prefix| codec.starts_with(prefix))
+    })
+}
+
+// A single step after the download. Each one produces a file
+// with the given extension.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostProcessingStep {
+    // Copies the streams into another container (e.g. MPEG-TS -> MP4).
+    Remux(String),
+    // Keeps only the audio stream, converted into <format>.
+    ExtractAudio {
+        ext: String,
+        format: String,
+        quality: Option<AudioQuality>,
+    },
+    // Keeps only the audio stream as it is.
+    CopyAudio(String),
+    // Re-encodes the streams for another container.
+    Transcode(String),
+    // Adds tags, chapters and cover art to the file.
+    Embed {
+        ext: String,
+        embedding: Embedding,
+    },
+}
+
+// A short description for the verbose output, e.g. "Extracting the audio as mp3":
+impl fmt::Display for PostProcessingStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Remux(ext) => write!(f, "Remuxing into {}", ext),
+            Self::ExtractAudio { format, .. } => write!(f, "Extracting the audio as {}", format),
+            Self::CopyAudio(ext) => write!(f, "Copying the audio into {}", ext),
+            Self::Transcode(ext) => write!(f, "Transcoding into {}", ext),
+            Self::Embed { embedding, .. } => {
+                let mut parts = vec![];
+                if !embedding.tags.is_empty() {
+                    parts.push("metadata");
+                }
+                if !embedding.chapters.is_empty() {
+                    parts.push("chapters");
+                }
+                if embedding.thumbnail.is_some() {
+                    parts.push("thumbnail");
+                }
+                write!(f, "Embedding the {}", parts.join(", "))
+            }
+        }
+    }
+}
+
+impl PostProcessingStep {
+    pub fn ext(&self) -> &str {
+        match self {
+            Self::Remux(ext)
+            | Self::ExtractAudio { ext, .. }
+            | Self::CopyAudio(ext)
+            | Self::Transcode(ext)
+            | Self::Embed { ext, .. } => ext,
+        }
+    }
+
+    // returns the steps to turn the downloaded <targetfile> (with the
+    // codecs <vcodec> and <acodec>, if known) into what the user wants.
+    pub fn plan(
+        targetfile: &TargetFile,
+        vcodec: Option<&str>,
+        acodec: Option<&str>,
+        only_audio: bool,
+        audio_format: &str,
+        audio_quality: Option<AudioQuality>,
+    ) -> Vec<Self> {
+        if only_audio {
+            let ext = audio_ext(audio_format);
+            if targetfile.target_ext == ext {
+                return vec![];
+            }
+
+            // Re-encoding an AAC or Opus stream only makes it worse, unless
+            // the user asked for a certain quality:
+            if audio_quality.is_none()
+                && acodec.is_some_and(|acodec| can_copy_audio(acodec, audio_format))
+            {
+                return vec![Self::CopyAudio(ext)];
+            }
+
+            return vec![Self::ExtractAudio {
+                ext,
+                format: audio_format.to_string(),
+                quality: audio_quality,
+            }];
+        }
+
+        // MPEG-TS (from HLS playlists) is hardly playable as a file:
+        if targetfile.target_ext == "ts" {
+            return match fits_into_mp4(vcodec) && fits_into_mp4(acodec) {
+                true => vec![Self::Remux("mp4".to_string())],
+                false => vec![Self::Transcode("mp4".to_string())],
+            };
+        }
+
+        vec![]
+    }
+}
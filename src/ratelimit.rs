@@ -0,0 +1,229 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - ratelimit.rs file -
+
+// Caps how fast yaydl downloads. All readers of a Downloader take their
+// bytes from the same token bucket, so the limit holds for the sum of all
+// connections and playlist segments which are fetched at the same time.
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Reads are cut into pieces of about a tenth of a second's worth,
+// so slow limits do not come in bursts:
+const MIN_CHUNK: usize = 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    // None if there is no limit:
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Bytes per second:
+    rate: u64,
+    // May become negative: a reader which took more than there was
+    // waits until the bucket has been refilled that far.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    // returns a limiter for <rate> bytes per second (0 for no limit).
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: (rate > 0).then(|| {
+                Arc::new(Mutex::new(Bucket {
+                    rate,
+                    // Start empty, or the first second would be twice as fast:
+                    tokens: 0.0,
+                    refilled: Instant::now(),
+                }))
+            }),
+        }
+    }
+
+    // returns <inner>, limited to the rate of this limiter.
+    pub fn limit<R: Read>(&self, inner: R) -> Limited<R> {
+        Limited {
+            inner,
+            limiter: self.clone(),
+        }
+    }
+
+    // returns how many bytes a single read may take.
+    fn chunk_size(&self) -> usize {
+        match &self.bucket {
+            Some(bucket) => {
+                let rate = bucket.lock().unwrap().rate;
+                ((rate / 10) as usize).clamp(MIN_CHUNK, MAX_CHUNK)
+            }
+            None => usize::MAX,
+        }
+    }
+
+    // Takes <count> bytes from the bucket, waiting until they are there.
+    fn take(&self, count: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+
+        let wait = {
+            let mut bucket = bucket.lock().unwrap();
+            let now = Instant::now();
+            let rate = bucket.rate as f64;
+
+            // Refill, but never more than a second's worth, or a pause
+            // (e.g. between two videos) would be followed by a burst:
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate);
+            bucket.refilled = now;
+
+            bucket.tokens -= count as f64;
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / rate),
+                false => Duration::ZERO,
+            }
+        };
+
+        // Sleep without the lock, so the other readers can queue up
+        // behind us in the meantime:
+        thread::sleep(wait);
+    }
+}
+
+pub struct Limited<R> {
+    inner: R,
+    limiter: RateLimiter,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.limiter.chunk_size());
+        let n = self.inner.read(&mut buf[..len])?;
+        self.limiter.take(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(limiter: &RateLimiter) -> f64 {
+        limiter.bucket.as_ref().unwrap().lock().unwrap().tokens
+    }
+
+    // Pretends that the bucket was last refilled <ago>.
+    fn set_refilled(limiter: &RateLimiter, ago: Duration) {
+        limiter.bucket.as_ref().unwrap().lock().unwrap().refilled = Instant::now() - ago;
+    }
+
+    #[test]
+    fn no_limit() {
+        assert!(RateLimiter::new(0).bucket.is_none());
+        assert_eq!(RateLimiter::default().chunk_size(), usize::MAX);
+
+        let start = Instant::now();
+        let mut data = vec![];
+        RateLimiter::default()
+            .limit(io::repeat(1).take(1024 * 1024))
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data.len(), 1024 * 1024);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(RateLimiter::new(1000).chunk_size(), MIN_CHUNK);
+        assert_eq!(RateLimiter::new(100_000).chunk_size(), 10_000);
+        assert_eq!(RateLimiter::new(100_000_000).chunk_size(), MAX_CHUNK);
+
+        let mut buffer = vec![0; 1024 * 1024];
+        let n = RateLimiter::new(100_000)
+            .limit(io::repeat(1))
+            .read(&mut buffer)
+            .unwrap();
+        assert_eq!(n, 10_000);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(tokens(&limiter), 0.0);
+
+        set_refilled(&limiter, Duration::from_millis(500));
+        limiter.take(0);
+        assert!((500.0..550.0).contains(&tokens(&limiter)));
+
+        // What is there is taken without waiting:
+        let start = Instant::now();
+        limiter.take(500);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn refills_at_most_a_second() {
+        let limiter = RateLimiter::new(1000);
+        set_refilled(&limiter, Duration::from_secs(60));
+        limiter.take(0);
+        assert_eq!(tokens(&limiter), 1000.0);
+    }
+
+    #[test]
+    fn bursts_wait_for_the_rest() {
+        // A full bucket of 100 KB, but 150 KB are taken at once:
+        let limiter = RateLimiter::new(100_000);
+        set_refilled(&limiter, Duration::from_secs(60));
+
+        let start = Instant::now();
+        limiter.take(150_000);
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(450), "{:?}", waited);
+        assert!(waited < Duration::from_millis(1500), "{:?}", waited);
+    }
+
+    #[test]
+    fn threads_share_the_rate() {
+        // 4 x 10 KB at 100 KB/s take about 0.4 seconds together:
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let limiter = limiter.clone();
+                scope.spawn(move || {
+                    let mut data = vec![];
+                    limiter
+                        .limit(io::repeat(1).take(10_000))
+                        .read_to_end(&mut data)
+                        .unwrap();
+                    assert_eq!(data.len(), 10_000);
+                });
+            }
+        });
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(350), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
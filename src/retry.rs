@@ -0,0 +1,211 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - retry.rs file -
+
+// Servers have bad moments: a 503, a reset connection, a download that
+// stops halfway. Such errors are retried after a growing delay, errors
+// which would only happen again (e.g. a 404) are not.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+// How often a failed request is retried unless the user says otherwise:
+pub const DEFAULT_RETRIES: usize = 3;
+
+// The first retry waits about this long, every further one twice as long:
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Servers which want us to come back much later are not waited for that long:
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+#[derive(Debug, PartialEq)]
+enum Failure {
+    // Worth another try, if the server said when:
+    Retryable(Option<Duration>),
+    Permanent,
+}
+
+// returns whether <error> could go away by trying again.
+fn classify(error: &anyhow::Error) -> Failure {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<ureq::Error>() {
+            return match error {
+                // Timeouts, rate limits and server errors:
+                ureq::Error::Status(408 | 425 | 429 | 500 | 502 | 503 | 504, response) => {
+                    Failure::Retryable(response.header("Retry-After").and_then(parse_retry_after))
+                }
+                ureq::Error::Status(_, _) => Failure::Permanent,
+                ureq::Error::Transport(transport) => match transport.kind() {
+                    // A host which does not resolve will not start to:
+                    ureq::ErrorKind::Dns => Failure::Permanent,
+                    ureq::ErrorKind::ConnectionFailed
+                    | ureq::ErrorKind::ProxyConnect
+                    | ureq::ErrorKind::Io => Failure::Retryable(None),
+                    _ => Failure::Permanent,
+                },
+            };
+        }
+
+        // Errors while reading a response. (Writing the file fails with
+        // other kinds, which no retry can fix.)
+        if let Some(error) = cause.downcast_ref::<io::Error>() {
+            return match error.kind() {
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::UnexpectedEof => Failure::Retryable(None),
+                _ => Failure::Permanent,
+            };
+        }
+    }
+
+    Failure::Permanent
+}
+
+// Retry-After is either a number of seconds or a date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+            let date = SystemTime::from(date);
+            date.duration_since(SystemTime::now()).unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+// returns how long to wait before the <attempt>th retry (counting from 1):
+// twice as long as before, but only half of it for sure, so parallel
+// downloads which failed together do not come back together.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_DELAY);
+    let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    delay.mul_f64(0.5 + jitter / 2.0)
+}
+
+// returns what <f> returns, calling it up to <retries> more times as long as
+// it fails with retryable errors. <what> (e.g. "Fetching <url>") is what
+// the user is told to have failed, if <verbose> is set.
+pub fn with_retries<T>(
+    retries: usize,
+    verbose: bool,
+    what: &str,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        let error = match f() {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+
+        let retry_after = match classify(&error) {
+            Failure::Retryable(retry_after) if attempt < retries => retry_after,
+            _ => return Err(error),
+        };
+
+        attempt += 1;
+        let delay = retry_after.unwrap_or_else(|| backoff(attempt as u32));
+        if verbose {
+            eprintln!(
+                "{} failed ({:#}), retrying in {:.1} seconds ({}/{}) ...",
+                what,
+                error,
+                delay.as_secs_f64(),
+                attempt,
+                retries
+            );
+        }
+        thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16, retry_after: Option<&str>) -> anyhow::Error {
+        let header = retry_after
+            .map(|value| format!("Retry-After: {}\r\n", value))
+            .unwrap_or_default();
+        let response = format!("HTTP/1.1 {} Status\r\n{}\r\n", code, header)
+            .parse()
+            .unwrap();
+        ureq::Error::Status(code, response).into()
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(classify(&status(503, None)), Failure::Retryable(None));
+        assert_eq!(
+            classify(&status(429, Some("7"))),
+            Failure::Retryable(Some(Duration::from_secs(7)))
+        );
+        assert_eq!(classify(&status(404, None)), Failure::Permanent);
+
+        // A resolver which finds nothing fails without asking the network:
+        let dns = ureq::AgentBuilder::new()
+            .resolver(|_: &str| Ok(vec![]))
+            .build()
+            .get("http://example.com/")
+            .call()
+            .unwrap_err();
+        assert_eq!(dns.kind(), ureq::ErrorKind::Dns);
+        assert_eq!(classify(&dns.into()), Failure::Permanent);
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(classify(&reset.into()), Failure::Retryable(None));
+        let full = io::Error::from(io::ErrorKind::StorageFull);
+        assert_eq!(classify(&full.into()), Failure::Permanent);
+    }
+
+    #[test]
+    fn retries_only_what_may_go_away() {
+        let mut calls = 0;
+        let result: Result<()> = with_retries(3, false, "Testing", || {
+            calls += 1;
+            Err(status(404, None))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result = with_retries(3, false, "Testing", || {
+            calls += 1;
+            match calls {
+                1 => Err(status(503, Some("0"))),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - sanitize.rs file -
+
+// Video titles make bad file names. Everything that turns metadata
+// into a file name goes through this file.
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+// Characters which do not belong into file names on any system
+// (or which make them painful to use in a shell):
+const FORBIDDEN_CHARS: [char; 10] = ['/', '\\', '|', '\'', '"', ':', '?', '*', '<', '>'];
+
+// Names which Windows reserves for devices, even with an extension:
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Most file systems allow 255 bytes per file name. Leave some room for
+// the suffixes of temporary files (e.g. ".f137.webm.part"):
+const MAX_NAME_BYTES: usize = 200;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sanitizer {
+    // Only ASCII letters, digits, '_', '-' and '.'.
+    pub restrict: bool,
+    // Follow the rules of Windows, even on other systems.
+    pub windows: bool,
+}
+
+impl Sanitizer {
+    // returns <value> (e.g. a title) as a part of a single file name.
+    pub fn field(&self, value: &str) -> String {
+        // The same text should always give the same file name, no matter
+        // how it was composed:
+        let value: String = value
+            .nfc()
+            .filter(|c| !c.is_control() && !FORBIDDEN_CHARS.contains(c))
+            .collect();
+
+        let value = match self.restrict {
+            true => restrict(&value),
+            false => value.trim().to_string(),
+        };
+
+        // A title must not lead into another directory. A title in another
+        // script has nothing left in ASCII, which would give a hidden file
+        // (".mp4") shared by all such videos:
+        match value == "." || value == ".." || (self.restrict && value.is_empty()) {
+            true => "_".to_string(),
+            false => value,
+        }
+    }
+
+    // returns <path> (from an output template) with each of its
+    // components made safe to use.
+    pub fn path(&self, path: &str) -> String {
+        let components: Vec<String> = path
+            .split(['/', '\\'])
+            .enumerate()
+            .filter(|(i, component)| *i == 0 || !component.is_empty())
+            .map(|(i, component)| match component {
+                // The root of an absolute path,
+                "" if i == 0 => String::new(),
+                // the current and the parent directory:
+                "." | ".." => component.to_string(),
+                _ => self.component(component),
+            })
+            .collect();
+
+        components.join(std::path::MAIN_SEPARATOR_STR)
+    }
+
+    // returns <name> as a valid file or directory name.
+    fn component(&self, name: &str) -> String {
+        let mut name: String = name.nfc().filter(|c| !c.is_control()).collect();
+
+        // Keep the extension when the name is too long:
+        if name.len() > MAX_NAME_BYTES {
+            let (stem, ext) = match name.rsplit_once('.') {
+                Some((stem, ext)) if ext.len() < 16 => (stem, format!(".{}", ext)),
+                _ => (name.as_str(), String::new()),
+            };
+            name = format!("{}{}", truncate(stem, MAX_NAME_BYTES - ext.len()), ext);
+        }
+
+        let mut name = name.trim().to_string();
+        if self.windows || cfg!(windows) {
+            // Windows drops trailing dots and spaces, so "a." and "a" would clash:
+            name = name.trim_end_matches(['.', ' ']).to_string();
+
+            let base = name.split('.').next().unwrap_or("").trim_end();
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(base))
+            {
+                name.insert(0, '_');
+            }
+        }
+
+        match name.is_empty() || name.chars().all(|c| c == '.') {
+            true => "_".to_string(),
+            false => name,
+        }
+    }
+}
+
+// returns <value> in ASCII letters, digits, '_', '-' and '.' only.
+fn restrict(value: &str) -> String {
+    let mut restricted = String::new();
+
+    // "é" becomes "e" and a combining accent, which is dropped then:
+    for c in value.nfkd().filter(|c| !is_combining_mark(*c)) {
+        let c = match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        };
+
+        // No "___" for " - ":
+        if !(c == '_' && restricted.ends_with('_')) {
+            restricted.push(c);
+        }
+    }
+
+    restricted.trim_matches('_').to_string()
+}
+
+// returns the first (at most) <max_bytes> bytes of <value>
+// without splitting a character.
+fn truncate(value: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(value.len());
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::MAIN_SEPARATOR_STR as SEP;
+
+    const WINDOWS: Sanitizer = Sanitizer {
+        restrict: false,
+        windows: true,
+    };
+
+    #[test]
+    fn fields() {
+        let sanitizer = Sanitizer::default();
+        assert_eq!(sanitizer.field(" AC/DC: Live? "), "ACDC Live");
+        assert_eq!(sanitizer.field("a\nb\tc"), "abc");
+        assert_eq!(sanitizer.field(".."), "_");
+
+        // Composed and decomposed accents give the same name:
+        assert_eq!(sanitizer.field("Cafe\u{301}"), sanitizer.field("Caf\u{e9}"));
+    }
+
+    #[test]
+    fn restricted_fields() {
+        let sanitizer = Sanitizer {
+            restrict: true,
+            windows: false,
+        };
+        assert_eq!(
+            sanitizer.field("Café del Mar - Best of (2024)"),
+            "Cafe_del_Mar_-_Best_of_2024"
+        );
+        assert_eq!(sanitizer.field("日本語"), "_");
+        assert_eq!(sanitizer.field("!?!"), "_");
+    }
+
+    #[test]
+    fn paths() {
+        let sanitizer = Sanitizer::default();
+        assert_eq!(
+            sanitizer.path("/videos//new/a.mp4"),
+            ["", "videos", "new", "a.mp4"].join(SEP)
+        );
+        assert_eq!(sanitizer.path("../a.mp4"), ["..", "a.mp4"].join(SEP));
+        assert_eq!(sanitizer.path("..."), "_");
+    }
+
+    #[test]
+    fn windows_names() {
+        assert_eq!(WINDOWS.path("con.mp4"), "_con.mp4");
+        assert_eq!(WINDOWS.path("Aux"), "_Aux");
+        assert_eq!(WINDOWS.path("COM10.mp4"), "COM10.mp4");
+        assert_eq!(WINDOWS.path("title. "), "title");
+        assert_eq!(WINDOWS.path("..."), "_");
+    }
+
+    #[test]
+    fn long_names_keep_their_extension() {
+        // 1 + 150 * 2 bytes; the limit falls into the middle of an "é":
+        let name = format!("a{}.mp4", "é".repeat(150));
+        let path = Sanitizer::default().path(&name);
+
+        assert_eq!(path.len(), 1 + 97 * 2 + ".mp4".len());
+        assert!(path.starts_with("aé"));
+        assert!(path.ends_with("é.mp4"));
+    }
+}
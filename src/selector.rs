@@ -0,0 +1,377 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - selector.rs file -
+
+// Format selectors choose one of the formats a site definition found.
+// They look like this:
+//
+//   best                  the best format with both audio and video
+//   worst                 the worst format with both audio and video
+//   bestvideo, bestaudio  the best video-only or audio-only format
+//   hls-720p, mp4         the format with this ID or file extension
+//   best[height<=720]     filters in brackets, see Filter below
+//   bestvideo+bestaudio   two formats which are merged after the download
+//   bestaudio/best        alternatives, the first one that matches wins
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use crate::definitions::VideoFormat;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Streams {
+    Both,
+    VideoOnly,
+    AudioOnly,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Base {
+    Best(Streams),
+    Worst(Streams),
+    IdOrExt(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+const FILTER_RE: &str =
+    r"^\s*(?P<FIELD>\w+)\s*(?P<OP><=|>=|!=|\^=|\$=|\*=|<|>|=)(?P<UNKNOWN>\?)?\s*(?P<VALUE>.*?)\s*$";
+
+// A filter like [height<=720], [ext=mp4] or [vcodec^=avc1].
+// With a "?" after the operator ([height<=?720]), formats
+// which do not know the field will pass as well.
+#[derive(Debug, Clone)]
+struct Filter {
+    field: String,
+    op: Op,
+    value: String,
+    allow_unknown: bool,
+}
+
+// A single format, e.g. bestvideo[height<=1080].
+#[derive(Debug, Clone)]
+struct Selection {
+    base: Base,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FormatSelector {
+    // Every alternative selects one format or two formats to merge.
+    alternatives: Vec<Vec<Selection>>,
+}
+
+impl Filter {
+    fn parse(text: &str) -> Result<Self> {
+        let filter_re = Regex::new(FILTER_RE).unwrap();
+        let captures = filter_re
+            .captures(text)
+            .ok_or_else(|| anyhow!("Invalid format filter: [{}]", text))?;
+
+        let op = match &captures["OP"] {
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            "=" => Op::Eq,
+            "!=" => Op::Ne,
+            "^=" => Op::StartsWith,
+            "$=" => Op::EndsWith,
+            _ => Op::Contains,
+        };
+
+        let field = captures["FIELD"].to_string();
+        let is_numeric = matches!(field.as_str(), "width" | "height" | "bitrate");
+        let is_string = matches!(field.as_str(), "id" | "ext" | "vcodec" | "acodec");
+
+        if !is_numeric && !is_string {
+            return Err(anyhow!("Unknown format field: {}", field));
+        }
+
+        if is_numeric && matches!(op, Op::StartsWith | Op::EndsWith | Op::Contains) {
+            return Err(anyhow!(
+                "{} can only be compared with <, <=, >, >=, = or !=",
+                field
+            ));
+        }
+
+        if is_numeric && captures["VALUE"].parse::<u64>().is_err() {
+            return Err(anyhow!("{} must be compared with a number", field));
+        }
+
+        Ok(Self {
+            field,
+            op,
+            value: captures["VALUE"].to_string(),
+            allow_unknown: captures.name("UNKNOWN").is_some(),
+        })
+    }
+
+    fn matches(&self, format: &VideoFormat) -> bool {
+        let number = match self.field.as_str() {
+            "width" => Some(format.width),
+            "height" => Some(format.height),
+            "bitrate" => Some(format.bitrate),
+            _ => None,
+        };
+
+        if let Some(number) = number {
+            let Some(number) = number else {
+                return self.allow_unknown;
+            };
+            let value = self.value.parse::<u64>().unwrap_or(0);
+
+            return match self.op {
+                Op::Lt => number < value,
+                Op::Le => number <= value,
+                Op::Gt => number > value,
+                Op::Ge => number >= value,
+                Op::Eq => number == value,
+                Op::Ne => number != value,
+                _ => false,
+            };
+        }
+
+        let text = match self.field.as_str() {
+            "id" => Some(&format.id),
+            "ext" => Some(&format.ext),
+            "vcodec" => format.vcodec.as_ref(),
+            _ => format.acodec.as_ref(),
+        };
+
+        let Some(text) = text else {
+            return self.allow_unknown;
+        };
+
+        match self.op {
+            Op::Eq => *text == self.value,
+            Op::Ne => *text != self.value,
+            Op::StartsWith => text.starts_with(&self.value),
+            Op::EndsWith => text.ends_with(&self.value),
+            Op::Contains => text.contains(&self.value),
+            _ => false,
+        }
+    }
+}
+
+impl Selection {
+    fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let (base, mut rest) = text.split_at(text.find('[').unwrap_or(text.len()));
+
+        let base = match base.trim() {
+            "" | "best" | "b" => Base::Best(Streams::Both),
+            "worst" | "w" => Base::Worst(Streams::Both),
+            "bestvideo" | "bv" => Base::Best(Streams::VideoOnly),
+            "worstvideo" | "wv" => Base::Worst(Streams::VideoOnly),
+            "bestaudio" | "ba" => Base::Best(Streams::AudioOnly),
+            "worstaudio" | "wa" => Base::Worst(Streams::AudioOnly),
+            other => Base::IdOrExt(other.to_string()),
+        };
+
+        let mut filters = vec![];
+        while !rest.is_empty() {
+            let end = match (rest.starts_with('['), rest.find(']')) {
+                (true, Some(end)) => end,
+                _ => return Err(anyhow!("Invalid format selector: {}", text)),
+            };
+            filters.push(Filter::parse(&rest[1..end])?);
+            rest = &rest[end + 1..];
+        }
+
+        Ok(Self { base, filters })
+    }
+
+    fn select<'a>(&self, formats: &'a [VideoFormat]) -> Option<&'a VideoFormat> {
+        let mut candidates: Vec<(usize, &VideoFormat)> = formats
+            .iter()
+            .enumerate()
+            .filter(|(_, format)| match &self.base {
+                Base::IdOrExt(id) => format.id == *id || format.ext == *id,
+                Base::Best(streams) | Base::Worst(streams) => match streams {
+                    Streams::Both => !format.video_only && !format.audio_only,
+                    Streams::VideoOnly => format.video_only,
+                    Streams::AudioOnly => format.audio_only,
+                },
+            })
+            .filter(|(_, format)| self.filters.iter().all(|filter| filter.matches(format)))
+            .collect();
+
+        // The site definitions sort their formats from the worst to the best one.
+        // Known resolutions and bitrates take precedence over that order though:
+        candidates.sort_by_key(|(index, format)| {
+            (
+                format.height.unwrap_or(0),
+                format.width.unwrap_or(0),
+                format.bitrate.unwrap_or(0),
+                *index,
+            )
+        });
+
+        match self.base {
+            Base::Worst(_) => candidates.first(),
+            _ => candidates.last(),
+        }
+        .map(|(_, format)| *format)
+    }
+}
+
+// Without a selector, the best format with both audio and video is taken:
+impl Default for FormatSelector {
+    fn default() -> Self {
+        Self {
+            alternatives: vec![vec![Selection {
+                base: Base::Best(Streams::Both),
+                filters: vec![],
+            }]],
+        }
+    }
+}
+
+impl FormatSelector {
+    pub fn parse(text: &str) -> Result<Self> {
+        let alternatives = text
+            .split('/')
+            .map(|alternative| {
+                let selections = alternative
+                    .split('+')
+                    .map(Selection::parse)
+                    .collect::<Result<Vec<_>>>()?;
+
+                match selections.len() {
+                    1 | 2 => Ok(selections),
+                    _ => Err(anyhow!("Only two formats can be merged: {}", alternative)),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { alternatives })
+    }
+
+    // returns the first format that matches one of the alternatives.
+    // Alternatives which merge formats are skipped.
+    pub fn select<'a>(&self, formats: &'a [VideoFormat]) -> Option<&'a VideoFormat> {
+        self.alternatives
+            .iter()
+            .filter(|selections| selections.len() == 1)
+            .find_map(|selections| selections[0].select(formats))
+    }
+
+    // returns the formats of the first alternative which matches completely:
+    // either a single format or a video and an audio format to merge.
+    pub fn select_all<'a>(&self, formats: &'a [VideoFormat]) -> Option<Vec<&'a VideoFormat>> {
+        self.alternatives.iter().find_map(|selections| {
+            selections
+                .iter()
+                .map(|selection| selection.select(formats))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(id: &str, height: Option<u64>, video_only: bool, audio_only: bool) -> VideoFormat {
+        VideoFormat {
+            id: id.to_string(),
+            ext: if audio_only { "m4a" } else { "mp4" }.to_string(),
+            height,
+            video_only,
+            audio_only,
+            ..Default::default()
+        }
+    }
+
+    fn formats() -> Vec<VideoFormat> {
+        vec![
+            format("360p", Some(360), false, false),
+            format("1080p-video", Some(1080), true, false),
+            format("720p", Some(720), false, false),
+            format("audio", None, false, true),
+        ]
+    }
+
+    fn ids(selector: &str, formats: &[VideoFormat]) -> Option<Vec<String>> {
+        FormatSelector::parse(selector)
+            .unwrap()
+            .select_all(formats)
+            .map(|formats| formats.iter().map(|format| format.id.clone()).collect())
+    }
+
+    #[test]
+    fn best_and_worst_prefer_known_resolutions() {
+        assert_eq!(ids("best", &formats()).unwrap(), ["720p"]);
+        assert_eq!(ids("worst", &formats()).unwrap(), ["360p"]);
+        assert_eq!(ids("bestvideo", &formats()).unwrap(), ["1080p-video"]);
+        assert_eq!(ids("bestaudio", &formats()).unwrap(), ["audio"]);
+    }
+
+    #[test]
+    fn merges_video_and_audio_or_falls_back() {
+        let formats = formats();
+        assert_eq!(
+            ids("bestvideo+bestaudio/best", &formats).unwrap(),
+            ["1080p-video", "audio"]
+        );
+
+        // Without an audio-only format, the second alternative wins:
+        let without_audio: Vec<_> = formats.into_iter().filter(|f| !f.audio_only).collect();
+        assert_eq!(
+            ids("bestvideo+bestaudio/best", &without_audio).unwrap(),
+            ["720p"]
+        );
+
+        // select() skips the merging alternatives:
+        let selector = FormatSelector::parse("bestvideo+bestaudio/worst").unwrap();
+        assert_eq!(selector.select(&without_audio).unwrap().id, "360p");
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(ids("best[height<=480]", &formats()).unwrap(), ["360p"]);
+        assert_eq!(ids("best[height>1080]", &formats()), None);
+        assert_eq!(ids("best[id^=72]", &formats()).unwrap(), ["720p"]);
+        assert_eq!(
+            ids("bestaudio[height<=?480]", &formats()).unwrap(),
+            ["audio"]
+        );
+        assert_eq!(ids("bestaudio[height<=480]", &formats()), None);
+        assert_eq!(ids("m4a", &formats()).unwrap(), ["audio"]);
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        assert!(FormatSelector::parse("best[foo=1]").is_err());
+        assert!(FormatSelector::parse("best[height^=7]").is_err());
+        assert!(FormatSelector::parse("best[height<=abc]").is_err());
+        assert!(FormatSelector::parse("best[height<=720").is_err());
+        assert!(FormatSelector::parse("bv+ba+best").is_err());
+    }
+}
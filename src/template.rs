@@ -0,0 +1,140 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - template.rs file -
+
+// Output templates name the downloaded files, e.g.
+// "%(uploader)s/%(upload_date)s - %(title)s [%(id)s].%(ext)s".
+// A field is written as %(name)s or %(name)d, numbers can be padded
+// with %(name)05d. "%%" is a literal percent sign.
+
+use anyhow::{anyhow, Result};
+
+use crate::sanitize::Sanitizer;
+
+pub const DEFAULT_TEMPLATE: &str = "%(title)s.%(ext)s";
+
+// What is written for fields without a value:
+const MISSING_VALUE: &str = "NA";
+
+// returns <template> with all fields replaced by what <field> returns for them,
+// cleaned by <sanitizer>. Path separators in <template> are kept, the ones in
+// the field values are not.
+pub fn render(
+    template: &str,
+    sanitizer: &Sanitizer,
+    field: impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+    let invalid = |reason: &str| anyhow!("Invalid output template \"{}\": {}", template, reason);
+
+    let mut rendered = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            rendered.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('%') => {
+                rendered.push('%');
+                continue;
+            }
+            Some('(') => {}
+            _ => return Err(invalid("a field must look like %(name)s")),
+        }
+
+        let name: String = chars.by_ref().take_while(|c| *c != ')').collect();
+        let mut width = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            width.push(digit);
+        }
+
+        let value = field(&name).map(|value| sanitizer.field(&value));
+        let value = match (chars.next(), value) {
+            (Some('s' | 'd'), None) => MISSING_VALUE.to_string(),
+            (Some('s'), Some(value)) => value,
+            (Some('d'), Some(value)) => {
+                let zero_padded = width.starts_with('0');
+                match (width.parse::<usize>(), value.parse::<u64>()) {
+                    (Ok(width), Ok(number)) if zero_padded => format!("{:0width$}", number),
+                    (Ok(width), Ok(number)) => format!("{:width$}", number),
+                    _ => value,
+                }
+            }
+            _ => return Err(invalid(&format!("%({}) needs a type (s or d)", name))),
+        };
+
+        rendered.push_str(&value);
+    }
+
+    Ok(sanitizer.path(&rendered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::MAIN_SEPARATOR_STR as SEP;
+
+    fn field(name: &str) -> Option<String> {
+        match name {
+            "title" => Some("AC/DC: Live".to_string()),
+            "uploader" => Some("Someone".to_string()),
+            "id" => Some("abc".to_string()),
+            "ext" => Some("mp4".to_string()),
+            "height" => Some("720".to_string()),
+            "duration" => Some("42".to_string()),
+            _ => None,
+        }
+    }
+
+    fn render_default(template: &str) -> String {
+        render(template, &Sanitizer::default(), field).unwrap()
+    }
+
+    #[test]
+    fn fields_and_directories() {
+        assert_eq!(render_default(DEFAULT_TEMPLATE), "ACDC Live.mp4");
+
+        // The "/" of the title does not make another directory:
+        assert_eq!(
+            render_default("%(uploader)s/%(title)s [%(id)s].%(ext)s"),
+            format!("Someone{}ACDC Live [abc].mp4", SEP)
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(render_default("%(duration)05d"), "00042");
+        assert_eq!(render_default("x%(duration)5d"), "x   42");
+        assert_eq!(render_default("%(height)dp"), "720p");
+        // Text stays text:
+        assert_eq!(render_default("%(id)05d"), "abc");
+    }
+
+    #[test]
+    fn missing_fields_and_percent_signs() {
+        assert_eq!(render_default("%(upload_date)s - 100%%"), "NA - 100%");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let sanitizer = Sanitizer::default();
+        assert!(render("%(title)", &sanitizer, field).is_err());
+        assert!(render("%(title)x", &sanitizer, field).is_err());
+        assert!(render("50% off", &sanitizer, field).is_err());
+    }
+}
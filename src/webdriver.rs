@@ -0,0 +1,104 @@
+/*
+ * The contents of this file are subject to the terms of the
+ * Common Development and Distribution License, Version 1.0 only
+ * (the "License").  You may not use this file except in compliance
+ * with the License.
+ *
+ * See the file LICENSE in this distribution for details.
+ * A copy of the CDDL is also available via the Internet at
+ * http://www.opensource.org/licenses/cddl1.txt
+ *
+ * When distributing Covered Code, include this CDDL HEADER in each
+ * file and include the contents of the LICENSE file from this
+ * distribution.
+ */
+
+// Yet Another Youtube Down Loader
+// - webdriver.rs file -
+
+// Starting a browser takes a while, so all pages which need one are
+// opened in the same web driver session. It is ended when the last
+// Downloader which uses it is gone.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use fantoccini::{Client, ClientBuilder};
+use tokio::runtime::{self, Runtime};
+
+#[derive(Clone, Default)]
+pub struct WebDriver {
+    // None until the first page is opened:
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+struct Session {
+    runtime: Runtime,
+    client: Client,
+}
+
+impl fmt::Debug for WebDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebDriver")
+            .field("connected", &self.session.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl WebDriver {
+    // returns the source of <url> after running <scripts> on it,
+    // using the web driver on <port>.
+    pub fn source(&self, port: u16, url: &str, scripts: &[&str]) -> Result<String> {
+        let mut session = self.session.lock().unwrap();
+        if session.is_none() {
+            *session = Some(Session::connect(port)?);
+        }
+
+        let Session { runtime, client } = session.as_ref().unwrap();
+        let result = runtime.block_on(async {
+            client
+                .goto(url)
+                .await
+                .with_context(|| format!("Could not go to {}", url))?;
+
+            for script in scripts {
+                client
+                    .execute(script, vec![])
+                    .await
+                    .with_context(|| format!("Could not run a script on {}", url))?;
+            }
+
+            client
+                .source()
+                .await
+                .context("Could not read the site source")
+        });
+
+        // The browser might be gone, try a new session next time:
+        if result.is_err() {
+            *session = None;
+        }
+        result
+    }
+}
+
+impl Session {
+    fn connect(port: u16) -> Result<Self> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_time()
+            .enable_io()
+            .build()?;
+        let client = runtime
+            .block_on(ClientBuilder::native().connect(&format!("http://localhost:{}", port)))
+            .context("Failed to connect to the web driver")?;
+        Ok(Self { runtime, client })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Close the browser window again:
+        let _ = self.runtime.block_on(self.client.clone().close());
+    }
+}